@@ -0,0 +1,54 @@
+//! Fetches a page through a proxy requiring username/password authentication
+//!
+//! The credentials are read from `SOCKS_USER` and `SOCKS_PASS`:
+//!
+//! ```plain
+//! SOCKS_USER=alice SOCKS_PASS=secret \
+//!     cargo run -p socks5-client --example client_with_password -- 127.0.0.1:1080 example.com:80
+//! ```
+
+use std::{
+    env,
+    net::{SocketAddr, TcpStream},
+};
+
+use anyhow::{Context, Result};
+use async_io::Async;
+use futures_lite::{future::block_on, AsyncReadExt, AsyncWriteExt};
+use socks5::{address::Address, head::PasswordRequest};
+use socks5_client::ConnectBuilder;
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let proxy = args.next().unwrap_or_else(|| "127.0.0.1:1080".to_string());
+    let dest = args.next().unwrap_or_else(|| "example.com:80".to_string());
+    let (host, port) = dest.rsplit_once(':').context("destination without port")?;
+    let dest = match dest.parse::<SocketAddr>() {
+        Ok(addr) => addr.into(),
+        Err(_) => Address::from_domain(host, port.parse()?)?,
+    };
+
+    let username = env::var("SOCKS_USER").context("SOCKS_USER is not set")?;
+    let password = env::var("SOCKS_PASS").context("SOCKS_PASS is not set")?;
+    let credentials = PasswordRequest::new(username.as_bytes(), password.as_bytes())?;
+
+    block_on(async {
+        let mut stream = Async::<TcpStream>::connect(proxy.parse::<SocketAddr>()?).await?;
+        let connected = ConnectBuilder::new(dest)
+            .credentials(credentials)
+            .run(&mut stream)
+            .await?;
+        println!(
+            "connected with {}, bound address {}",
+            connected.method, connected.bound
+        );
+
+        stream
+            .write_all(format!("HEAD / HTTP/1.0\r\nHost: {host}\r\n\r\n").as_bytes())
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        print!("{response}");
+        Ok(())
+    })
+}
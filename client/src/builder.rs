@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use socks5::{
+    address::Address,
+    head::{
+        AuthenticationRequest, AuthenticationResponse, PasswordRequest, PasswordResponse,
+        TcpRequestHeader,
+    },
+    message::Method,
+    ser::Encode,
+};
+
+use crate::{pipelined, read, request, Error, ProxyHop, Result};
+
+/// Options of a connection through the proxy, executed by [`run`](ConnectBuilder::run)
+///
+/// By default only the `NONE` method is offered, or `NONE` and `PASSWORD` once
+/// credentials are set.
+#[derive(Clone, Debug)]
+pub struct ConnectBuilder {
+    dest: Address,
+    credentials: Option<PasswordRequest>,
+    methods: Option<Vec<Method>>,
+    connect_timeout: Option<Duration>,
+    hops: Vec<ProxyHop>,
+    optimistic: bool,
+    validate: bool,
+}
+
+impl ConnectBuilder {
+    pub fn new(dest: Address) -> ConnectBuilder {
+        ConnectBuilder {
+            dest,
+            credentials: None,
+            methods: None,
+            connect_timeout: None,
+            hops: Vec::new(),
+            optimistic: false,
+            validate: true,
+        }
+    }
+
+    /// Username and password sent if the server selects the `PASSWORD` method
+    pub fn credentials(mut self, credentials: PasswordRequest) -> ConnectBuilder {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Authentication methods offered to the server, in preference order
+    pub fn methods(mut self, methods: &[Method]) -> ConnectBuilder {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Maximum time to wait for each reply of the server
+    pub fn connect_timeout(mut self, timeout: Duration) -> ConnectBuilder {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sends the connect request along with the authentication request, without
+    /// waiting for the method selection, when `NONE` is the only method offered
+    ///
+    /// Saves a round trip with anonymous servers. Off by default, as strict
+    /// servers may not accept a request before replying to the method selection;
+    /// a server selecting another method fails the connection.
+    pub fn optimistic(mut self, optimistic: bool) -> ConnectBuilder {
+        self.optimistic = optimistic;
+        self
+    }
+
+    /// Checks each request with [`TcpRequestHeader::check_command`] before sending
+    /// anything, on by default; turned off, a nonsensical destination such as
+    /// `0.0.0.0:0` is left for the server to reject
+    pub fn validate(mut self, validate: bool) -> ConnectBuilder {
+        self.validate = validate;
+        self
+    }
+
+    /// Adds a proxy to go through, after the one the stream is connected to and
+    /// the hops added before
+    ///
+    /// The methods and credentials of this builder are for the first proxy, each
+    /// hop is authenticated with its own; the other options apply to all of them.
+    pub fn hop(mut self, hop: ProxyHop) -> ConnectBuilder {
+        self.hops.push(hop);
+        self
+    }
+
+    /// Runs the handshakes over `connect`, returns the outcome of the last proxy
+    pub async fn run<T>(&self, connect: &mut T) -> Result<Connected>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        // one buffer for all the messages sent over the stream
+        let mut buf = BytesMut::new();
+        // the proxy the stream is connected to so far
+        let mut auth = Auth {
+            methods: self.methods.as_deref(),
+            credentials: self.credentials.as_ref(),
+        };
+        for hop in &self.hops {
+            self.connect(connect, hop.address.clone(), auth, &mut buf)
+                .await
+                .map_err(|e| hop.error(e))?;
+            auth = Auth {
+                methods: hop.methods.as_deref(),
+                credentials: hop.credentials.as_ref(),
+            };
+        }
+        self.connect(connect, self.dest.clone(), auth, &mut buf)
+            .await
+    }
+
+    async fn connect<T>(
+        &self,
+        connect: &mut T,
+        dest: Address,
+        auth: Auth<'_>,
+        buf: &mut BytesMut,
+    ) -> Result<Connected>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let tcp_req = TcpRequestHeader::connect(dest);
+        if self.validate {
+            tcp_req.check_command()?;
+        }
+        let methods = match (auth.methods, auth.credentials) {
+            (Some(methods), _) => methods.to_vec(),
+            (None, Some(_)) => vec![Method::NONE, Method::PASSWORD],
+            (None, None) => vec![Method::NONE],
+        };
+        if self.optimistic && methods == [Method::NONE] {
+            let bound = pipelined(connect, tcp_req, self.connect_timeout, buf).await?;
+            let method = Method::NONE;
+            return Ok(Connected { bound, method });
+        }
+        let auth_req = AuthenticationRequest::new(methods.iter().copied());
+        auth_req.write_to_reusing(connect, buf).await?;
+        let auth_resp: AuthenticationResponse = read(connect, self.connect_timeout).await?;
+        let method = auth_resp.method();
+        match (method, auth.credentials) {
+            (Method::NONE, _) if methods.contains(&Method::NONE) => {}
+            (Method::PASSWORD, Some(credentials)) if methods.contains(&Method::PASSWORD) => {
+                credentials.write_to_reusing(connect, buf).await?;
+                let resp: PasswordResponse = read(connect, self.connect_timeout).await?;
+                if !resp.is_success() {
+                    return Err(Error::AuthFailed);
+                }
+            }
+            (selected, _) => return Err(Error::AuthRequired { selected }),
+        }
+        let bound = request(connect, tcp_req, self.connect_timeout, buf).await?;
+        Ok(Connected { bound, method })
+    }
+}
+
+/// Authentication options for one proxy
+#[derive(Clone, Copy)]
+struct Auth<'a> {
+    methods: Option<&'a [Method]>,
+    credentials: Option<&'a PasswordRequest>,
+}
+
+/// Outcome of a successful [`ConnectBuilder::run`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connected {
+    /// Address bound by the proxy (BND.ADDR)
+    pub bound: Address,
+    /// Authentication method selected by the proxy
+    pub method: Method,
+}
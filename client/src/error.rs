@@ -0,0 +1,96 @@
+use std::{
+    fmt::{Display, Formatter},
+    io,
+};
+
+use socks5::{
+    address::Address,
+    error::ErrorKind,
+    message::{Method, Replies},
+};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error of a connection through a proxy
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing the stream failed
+    Io(io::Error),
+    /// A message of the server is not valid or did not arrive in time, or the
+    /// GSSAPI exchange failed
+    Handshake(socks5::error::Error),
+    /// The server selected a method the client cannot use,
+    /// [`Method::NotAcceptable`] if it accepts none of the offered ones
+    AuthRequired { selected: Method },
+    /// The server rejected the username and password
+    AuthFailed,
+    /// The server answered the request with a failure reply, along with the
+    /// address it sent if not unspecified
+    Rejected {
+        dest: Address,
+        reply: Replies,
+        bound: Option<Address>,
+    },
+    /// Connecting through a hop of a chain failed
+    Hop {
+        address: Address,
+        source: Box<Error>,
+    },
+    /// A chain without any hop
+    EmptyChain,
+}
+
+impl Error {
+    /// Reply of the server if it rejected the request, at any hop of a chain
+    pub fn reply(&self) -> Option<Replies> {
+        match self {
+            Error::Rejected { reply, .. } => Some(*reply),
+            Error::Hop { source, .. } => source.reply(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Handshake(e) => write!(f, "handshake failed: {e}"),
+            Error::AuthRequired { selected } => {
+                write!(f, "server selected unusable auth method {selected}")
+            }
+            Error::AuthFailed => f.write_str("password authentication failed"),
+            Error::Rejected { dest, reply, .. } => write!(f, "connect to {dest} failed: {reply}"),
+            Error::Hop { address, .. } => write!(f, "connecting to proxy {address} failed"),
+            Error::EmptyChain => f.write_str("proxy chain is empty"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Handshake(e) => Some(e),
+            Error::Hop { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// I/O errors of the protocol crate are taken as [`Error::Io`]
+impl From<socks5::error::Error> for Error {
+    fn from(e: socks5::error::Error) -> Error {
+        match e.kind() {
+            ErrorKind::Io => Error::Io(e.into()),
+            _ => Error::Handshake(e),
+        }
+    }
+}
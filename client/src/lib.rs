@@ -1,39 +1,449 @@
-use anyhow::{bail, Result};
+use std::time::Duration;
+
+use bytes::BytesMut;
 use futures_lite::{AsyncReadExt, AsyncWriteExt};
 use socks5::{
     address::Address,
-    error::Error,
-    head::{AuthenticationRequest, AuthenticationResponse, TcpRequestHeader, TcpResponseHeader},
-    message::{Command, Method},
+    gssapi::{self, GssContext},
+    head::{
+        AuthenticationRequest, AuthenticationResponse, PasswordRequest, TcpRequestHeader,
+        TcpResponseHeader,
+    },
+    message::Method,
     ser::{Decode, Encode},
 };
 
-pub async fn connect_without_auth<T>(connect: &mut T, dest: Address) -> Result<()>
+pub use crate::{
+    builder::{ConnectBuilder, Connected},
+    error::{Error, Result},
+};
+
+mod builder;
+mod error;
+
+/// Connects to `dest` through the proxy, returns the address bound by the server (BND.ADDR)
+pub async fn connect_without_auth<T>(connect: &mut T, dest: Address) -> Result<Address>
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
 {
+    handshake(connect, TcpRequestHeader::connect(dest), None).await
+}
+
+/// Same as [`connect_without_auth`], but fails if a reply of the server does not
+/// arrive within `timeout`
+pub async fn connect_without_auth_timeout<T>(
+    connect: &mut T,
+    dest: Address,
+    timeout: Duration,
+) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    handshake(connect, TcpRequestHeader::connect(dest), Some(timeout)).await
+}
+
+async fn handshake<T>(
+    connect: &mut T,
+    tcp_req: TcpRequestHeader,
+    timeout: Option<Duration>,
+) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    tcp_req.check_command()?;
+    let mut buf = BytesMut::new();
     // authentication
-    let auth_req: AuthenticationRequest = [Method::NONE; 1].as_slice().into();
-    write(auth_req, connect).await?;
+    let auth_req = AuthenticationRequest::new([Method::NONE]);
+    auth_req.write_to_reusing(connect, &mut buf).await?;
+    let auth_resp: AuthenticationResponse = read(connect, timeout).await?;
+    if auth_resp.required_authentication() {
+        let selected = auth_resp.method();
+        return Err(Error::AuthRequired { selected });
+    }
+    request(connect, tcp_req, timeout, &mut buf).await
+}
+
+/// Connects to `dest` through the proxy, authenticating with GSSAPI
+///
+/// `context` is the initiator side of the security context, see [`socks5::gssapi`].
+pub async fn connect_with_gssapi<T>(
+    connect: &mut T,
+    dest: Address,
+    context: &mut dyn GssContext,
+) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let tcp_req = TcpRequestHeader::connect(dest);
+    tcp_req.check_command()?;
+    let mut buf = BytesMut::new();
+    let auth_req = AuthenticationRequest::new([Method::GSSAPI]);
+    auth_req.write_to_reusing(connect, &mut buf).await?;
     let auth_resp = AuthenticationResponse::read(connect).await?;
+    if auth_resp.method() != Method::GSSAPI {
+        let selected = auth_resp.method();
+        return Err(Error::AuthRequired { selected });
+    }
+    gssapi::establish(context, connect, true).await?;
+    request(connect, tcp_req, None, &mut buf).await
+}
+
+/// Sends the request, encoded into `buf`, and reads the response
+async fn request<T>(
+    connect: &mut T,
+    tcp_req: TcpRequestHeader,
+    timeout: Option<Duration>,
+    buf: &mut BytesMut,
+) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    tcp_req.write_to_reusing(connect, buf).await?;
+    response(connect, &tcp_req, timeout).await
+}
+
+/// Sends the authentication request offering only `NONE` and the request in a
+/// single write encoded into `buf`, then reads both replies, saving a round trip
+async fn pipelined<T>(
+    connect: &mut T,
+    tcp_req: TcpRequestHeader,
+    timeout: Option<Duration>,
+    buf: &mut BytesMut,
+) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let auth_req = AuthenticationRequest::new([Method::NONE]);
+    buf.clear();
+    auth_req.as_bytes_into(buf);
+    tcp_req.as_bytes_into(buf);
+    connect.write_all(buf).await?;
+    connect.flush().await?;
+    let auth_resp: AuthenticationResponse = read(connect, timeout).await?;
     if auth_resp.required_authentication() {
-        bail!("server does not support none password auth method");
+        let selected = auth_resp.method();
+        return Err(Error::AuthRequired { selected });
     }
+    response(connect, &tcp_req, timeout).await
+}
 
-    // requests
-    let tcp_req = TcpRequestHeader::new(Command::Connect, dest);
-    write(tcp_req, connect).await?;
-    let tcp_resp = TcpResponseHeader::read(connect).await?;
+/// Reads the response to `tcp_req`
+async fn response<T>(
+    connect: &mut T,
+    tcp_req: &TcpRequestHeader,
+    timeout: Option<Duration>,
+) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let tcp_resp: TcpResponseHeader = read(connect, timeout).await?;
     if tcp_resp.is_success() {
-        Ok(())
-    } else {
-        let e: Error = tcp_resp.reply.into();
-        bail!("connect failure: {e}");
+        return Ok(tcp_resp.into_address());
+    }
+    let (reply, bound) = tcp_resp.into_parts();
+    Err(Error::Rejected {
+        dest: tcp_req.address().clone(),
+        reply,
+        bound: (!bound.is_unspecified()).then_some(bound),
+    })
+}
+
+/// Reads a message, within `timeout` if given
+async fn read<D: Decode<T>, T: AsyncReadExt + Unpin>(
+    connect: &mut T,
+    timeout: Option<Duration>,
+) -> socks5::error::Result<D> {
+    match timeout {
+        Some(timeout) => D::read_timeout(connect, timeout).await,
+        None => D::read(connect).await,
+    }
+}
+
+/// Same as [`connect_without_auth`], over a tokio stream
+#[cfg(feature = "tokio")]
+pub async fn connect_without_auth_tokio<T>(connect: &mut T, dest: Address) -> Result<Address>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    connect_without_auth(&mut socks5::compat::TokioIo(connect), dest).await
+}
+
+/// A SOCKS5 proxy in a chain
+///
+/// Offers only the `NONE` method by default, or `NONE` and `PASSWORD` once
+/// credentials are set, like [`ConnectBuilder`].
+#[derive(Clone, Debug)]
+pub struct ProxyHop {
+    /// Address of the proxy, as reached from the previous hop
+    pub address: Address,
+    /// Authentication methods offered to this proxy, in preference order
+    pub methods: Option<Vec<Method>>,
+    /// Username and password sent if this proxy selects the `PASSWORD` method
+    pub credentials: Option<PasswordRequest>,
+}
+
+impl ProxyHop {
+    pub fn new(address: Address) -> ProxyHop {
+        ProxyHop {
+            address,
+            methods: None,
+            credentials: None,
+        }
     }
+
+    pub fn methods(mut self, methods: &[Method]) -> ProxyHop {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    pub fn credentials(mut self, credentials: PasswordRequest) -> ProxyHop {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    fn error(&self, source: Error) -> Error {
+        Error::Hop {
+            address: self.address.clone(),
+            source: Box::new(source),
+        }
+    }
+}
+
+/// Connects to `dest` through a chain of proxies
+///
+/// `connect` must already be connected to the first hop, each hop is then asked to
+/// connect to the next one, and the last hop to `dest`, each one authenticated with
+/// its own methods and credentials. All handshakes run over the same stream, so
+/// TLS-wrapped hops are out of scope for this helper. Returns the address bound by
+/// the last hop.
+pub async fn connect_chain<T>(connect: &mut T, hops: &[ProxyHop], dest: Address) -> Result<Address>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let (first, rest) = hops.split_first().ok_or(Error::EmptyChain)?;
+    let mut builder = ConnectBuilder::new(dest);
+    builder = match &first.methods {
+        Some(methods) => builder.methods(methods),
+        None => builder,
+    };
+    builder = match &first.credentials {
+        Some(credentials) => builder.credentials(credentials.clone()),
+        None => builder,
+    };
+    for hop in rest {
+        builder = builder.hop(hop.clone());
+    }
+    Ok(builder.run(connect).await?.bound)
 }
 
-async fn write<T: Encode, C: AsyncWriteExt + Unpin>(head: T, c: &mut C) -> Result<()> {
-    c.write_all(&head.as_bytes()).await?;
-    c.flush().await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use async_io::Async;
+    use futures_lite::{
+        future::{block_on, zip},
+        AsyncRead, AsyncWrite,
+    };
+    use socks5::{head::PasswordResponse, message::Replies};
+
+    use super::*;
+
+    fn pair() -> (Async<TcpStream>, Async<TcpStream>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Async::new(client).unwrap(), Async::new(server).unwrap())
+    }
+
+    /// Plays one proxy requiring `credentials`, expects a request to `dest`
+    async fn serve_hop(
+        stream: &mut Async<TcpStream>,
+        credentials: &PasswordRequest,
+        dest: &Address,
+    ) {
+        let auth_req = AuthenticationRequest::read(stream).await.unwrap();
+        assert!(auth_req.methods().contains(&Method::PASSWORD));
+        let auth_resp = AuthenticationResponse::from(Method::PASSWORD);
+        auth_resp.write_to(stream).await.unwrap();
+        let password_req = PasswordRequest::read(stream).await.unwrap();
+        assert_eq!(&password_req, credentials);
+        PasswordResponse::success().write_to(stream).await.unwrap();
+        let tcp_req = TcpRequestHeader::read(stream).await.unwrap();
+        assert_eq!(tcp_req.address(), dest);
+        let tcp_resp = TcpResponseHeader::success(Address::unspecified_v4(0));
+        tcp_resp.write_to(stream).await.unwrap();
+    }
+
+    #[test]
+    fn chain_authenticates_each_hop_with_its_credentials() {
+        let alice = PasswordRequest::new(b"alice", b"first").unwrap();
+        let bob = PasswordRequest::new(b"bob", b"second").unwrap();
+        let hops = [
+            ProxyHop::new(Address::from(SocketAddrV4::new(
+                Ipv4Addr::new(10, 0, 0, 1),
+                1080,
+            )))
+            .credentials(alice.clone()),
+            ProxyHop::new(Address::from(SocketAddrV4::new(
+                Ipv4Addr::new(10, 0, 0, 2),
+                1080,
+            )))
+            .credentials(bob.clone()),
+        ];
+        let dest = Address::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80));
+        let (mut client, mut server) = pair();
+        let proxies = async {
+            serve_hop(&mut server, &alice, &hops[1].address).await;
+            serve_hop(&mut server, &bob, &dest).await;
+        };
+        let (bound, ()) = block_on(zip(
+            connect_chain(&mut client, &hops, dest.clone()),
+            proxies,
+        ));
+        assert_eq!(bound.unwrap(), Address::unspecified_v4(0));
+    }
+
+    #[test]
+    fn empty_chain_is_an_error() {
+        let (mut client, _server) = pair();
+        let dest = Address::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80));
+        let result = block_on(connect_chain(&mut client, &[], dest));
+        assert!(matches!(result, Err(Error::EmptyChain)));
+    }
+
+    #[test]
+    fn refused_connection_is_rejected() {
+        let dest = Address::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80));
+        let (mut client, mut server) = pair();
+        let proxy = async {
+            let auth_req = AuthenticationRequest::read(&mut server).await.unwrap();
+            assert!(auth_req.methods().contains(&Method::NONE));
+            let auth_resp = AuthenticationResponse::from(Method::NONE);
+            auth_resp.write_to(&mut server).await.unwrap();
+            TcpRequestHeader::read(&mut server).await.unwrap();
+            let tcp_resp = TcpResponseHeader::failure(Replies::ConnectionRefused);
+            tcp_resp.write_to(&mut server).await.unwrap();
+        };
+        let (result, ()) = block_on(zip(connect_without_auth(&mut client, dest.clone()), proxy));
+        match result {
+            Err(Error::Rejected {
+                dest: rejected,
+                reply: Replies::ConnectionRefused,
+                bound: None,
+            }) => assert_eq!(rejected, dest),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    /// Stream recording the buffers written in each call
+    struct Recording<'a> {
+        inner: &'a mut Async<TcpStream>,
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl AsyncRead for Recording<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut *self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Recording<'_> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = &mut *self;
+            let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = poll {
+                this.writes.push(buf[..n].to_vec());
+            }
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.inner).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.inner).poll_close(cx)
+        }
+    }
+
+    #[test]
+    fn optimistic_mode_pipelines_the_request() {
+        let dest = Address::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80));
+        let auth_req = AuthenticationRequest::new([Method::NONE]);
+        let mut pipelined = auth_req.as_bytes().to_vec();
+        pipelined.extend_from_slice(&TcpRequestHeader::connect(dest.clone()).as_bytes());
+        let bound = Address::from(SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 7), 4000));
+        for selected in [Method::NONE, Method::PASSWORD] {
+            let (mut client, mut server) = pair();
+            let mut recording = Recording {
+                inner: &mut client,
+                writes: Vec::new(),
+            };
+            let builder = ConnectBuilder::new(dest.clone()).optimistic(true);
+            let proxy = async {
+                // both messages are sent before the method is selected
+                let mut received = vec![0; pipelined.len()];
+                server.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, pipelined);
+                let auth_resp = AuthenticationResponse::from(selected);
+                auth_resp.write_to(&mut server).await.unwrap();
+                let tcp_resp = TcpResponseHeader::success(bound.clone());
+                tcp_resp.write_to(&mut server).await.unwrap();
+            };
+            let (connected, ()) = block_on(zip(builder.run(&mut recording), proxy));
+            assert_eq!(recording.writes, [pipelined.clone()]);
+            match (selected, connected) {
+                (Method::NONE, Ok(connected)) => {
+                    assert_eq!(connected.bound, bound);
+                    assert_eq!(connected.method, Method::NONE);
+                }
+                (Method::PASSWORD, Err(Error::AuthRequired { selected })) => {
+                    assert_eq!(selected, Method::PASSWORD);
+                }
+                (_, connected) => panic!("unexpected result {connected:?}"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn connects_over_a_tokio_stream() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = socks5::compat::TokioIo(server);
+        let dest = Address::from_domain("example.com", 443).unwrap();
+        let bound = Address::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1080));
+        let server_side = async {
+            let auth = AuthenticationRequest::read(&mut server).await.unwrap();
+            assert_eq!(auth.methods(), [Method::NONE]);
+            let resp = AuthenticationResponse::from(Method::NONE);
+            resp.write_to(&mut server).await.unwrap();
+            let req = TcpRequestHeader::read(&mut server).await.unwrap();
+            let resp = TcpResponseHeader::success(bound.clone());
+            resp.write_to(&mut server).await.unwrap();
+            req
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (connected, req) = runtime.block_on(zip(
+            connect_without_auth_tokio(&mut client, dest.clone()),
+            server_side,
+        ));
+        assert_eq!(connected.unwrap(), bound);
+        assert_eq!(req.address(), &dest);
+    }
 }
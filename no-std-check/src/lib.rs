@@ -0,0 +1,58 @@
+//! Proof that the protocol crate builds without `std`
+//!
+//! Build it on its own, `cargo build -p no-std-check`, as other workspace members
+//! enable the `std` feature of `socks5`.
+
+#![no_std]
+
+use core::{fmt::Debug, hash::Hash};
+
+use socks5::{
+    address::Address,
+    error::Result,
+    head::{
+        AuthenticationRequest, AuthenticationResponse, PasswordRequest, PasswordResponse,
+        Socks4Response, TcpRequestHeader, TcpResponseHeader,
+    },
+    message::{Command, Message, Method, MethodSet, Phase, Replies},
+    ser::{DecodeSlice, Encode},
+};
+
+/// Decodes an authentication request, then a request header, both without the version
+pub fn parse(buf: &[u8]) -> Result<(AuthenticationRequest, TcpRequestHeader)> {
+    let (auth, n) = AuthenticationRequest::decode_slice(buf)?;
+    let (request, _) = TcpRequestHeader::decode_slice(&buf[n..])?;
+    Ok((auth, request))
+}
+
+/// Length of the request header on the wire, including the version
+pub fn request_len(request: &TcpRequestHeader) -> usize {
+    wire_len(request)
+}
+
+/// Length of any message on the wire, taken by reference through the blanket impl
+/// of `Encode` for `&T`
+pub fn wire_len(message: impl Encode) -> usize {
+    message.as_bytes().len()
+}
+
+/// Fails to build if a message type loses one of the standard derives
+#[allow(dead_code)]
+fn derives() {
+    fn check<T: Clone + Debug + Eq + Hash>() {}
+
+    check::<Address>();
+    check::<AuthenticationRequest>();
+    check::<AuthenticationResponse>();
+    check::<PasswordRequest>();
+    check::<PasswordResponse>();
+    check::<Socks4Response>();
+    check::<TcpRequestHeader>();
+    check::<TcpResponseHeader>();
+    check::<Command>();
+    check::<Method>();
+    check::<MethodSet>();
+    check::<Replies>();
+    check::<Message>();
+    check::<Phase>();
+}
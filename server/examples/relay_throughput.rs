@@ -0,0 +1,75 @@
+//! Measures the relay throughput over loopback, with the generic copy of
+//! `proxy_with_config` and the `splice(2)` path of `proxy_tcp`
+//!
+//! Run with `cargo run --release -p socks5-server --example relay_throughput`.
+
+use std::{
+    io::Read,
+    net::{Shutdown, TcpListener, TcpStream},
+    thread,
+    time::Instant,
+};
+
+use async_io::Async;
+use futures_lite::{future::block_on, AsyncReadExt, AsyncWriteExt};
+use socks5_server::ServerConfig;
+
+/// Bytes relayed by each run
+const TOTAL: usize = 8 << 30;
+
+fn main() {
+    for splice in [false, true] {
+        let sink = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink_port = sink.local_addr().unwrap().port().to_be_bytes();
+        let received = thread::spawn(move || {
+            let (mut stream, _) = sink.accept().unwrap();
+            let mut buf = vec![0; 64 * 1024];
+            let mut received = 0;
+            while received < TOTAL {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => received += n,
+                }
+            }
+            received
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, src) = proxy.accept().unwrap();
+            let mut stream = Async::new(stream).unwrap();
+            let config = ServerConfig::default();
+            block_on(async {
+                if splice {
+                    socks5_server::proxy_tcp(&mut stream, src, &config).await
+                } else {
+                    socks5_server::proxy_with_config(&mut stream, src, &config).await
+                }
+            })
+        });
+
+        let elapsed = block_on(async {
+            let mut client = Async::<TcpStream>::connect(proxy_addr).await.unwrap();
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+            let request = [5, 1, 0, 1, 127, 0, 0, 1, sink_port[0], sink_port[1]];
+            client.write_all(&request).await.unwrap();
+            client.read_exact(&mut [0; 10]).await.unwrap();
+
+            let buf = vec![0; 64 * 1024];
+            let start = Instant::now();
+            let mut sent = 0;
+            while sent < TOTAL {
+                client.write_all(&buf).await.unwrap();
+                sent += buf.len();
+            }
+            client.get_ref().shutdown(Shutdown::Write).unwrap();
+            assert_eq!(received.join().unwrap(), TOTAL);
+            start.elapsed()
+        });
+        let mib = (TOTAL >> 20) as f64;
+        let name = if splice { "splice" } else { "copy" };
+        println!("{name}: {:.0} MiB/s", mib / elapsed.as_secs_f64());
+    }
+}
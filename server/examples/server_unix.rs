@@ -0,0 +1,46 @@
+//! Local-only proxy accepting clients over a Unix domain socket, a name starting
+//! with `@` is bound in the abstract namespace on Linux
+//!
+//! ```plain
+//! cargo run -p socks5-server --example server_unix -- @socks5
+//! ```
+
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    use std::{env, os::unix::net::UnixListener, thread};
+
+    use async_io::Async;
+    use futures_lite::future::block_on;
+
+    let listen = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "socks5.sock".to_string());
+    let listener = match listen.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)?
+        }
+        _ => UnixListener::bind(&listen)?,
+    };
+    println!("listening on {listen}");
+    for stream in listener.incoming() {
+        let stream = stream?;
+        thread::spawn(move || {
+            let mut stream = Async::new(stream)?;
+            let result = block_on(socks5_server::proxy(&mut stream, None));
+            match &result {
+                Ok(served) => println!("{served}"),
+                Err(e) => eprintln!("{e:#}"),
+            }
+            result
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("Unix domain sockets are not supported on this platform");
+}
@@ -0,0 +1,44 @@
+//! Proxy requiring username/password authentication, with the single account
+//! given by `SOCKS_USER` and `SOCKS_PASS`
+//!
+//! ```plain
+//! SOCKS_USER=alice SOCKS_PASS=secret \
+//!     cargo run -p socks5-server --example server_with_password -- 127.0.0.1:1080
+//! ```
+
+use std::{env, net::TcpListener, thread};
+
+use anyhow::{Context, Result};
+use async_io::Async;
+use futures_lite::future::block_on;
+use socks5_server::{ServerConfig, Users};
+
+fn main() -> Result<()> {
+    let listen = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:1080".to_string());
+    let username = env::var("SOCKS_USER").context("SOCKS_USER is not set")?;
+    let password = env::var("SOCKS_PASS").context("SOCKS_PASS is not set")?;
+    let config = ServerConfig {
+        users: Some(Users::new().with_user(username.as_bytes(), password.as_bytes())),
+        ..ServerConfig::default()
+    };
+
+    let listener = TcpListener::bind(&listen)?;
+    println!("listening on {listen}");
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let src = stream.peer_addr()?;
+        let config = config.clone();
+        thread::spawn(move || {
+            let mut stream = Async::new(stream)?;
+            let result = block_on(socks5_server::proxy_tcp(&mut stream, src, &config));
+            match &result {
+                Ok(served) => println!("{src}: {served}"),
+                Err(e) => eprintln!("{e:#}"),
+            }
+            result
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use socks5::{address::Resolver, ser::DecodeLimits};
+
+use crate::{
+    relay::DEFAULT_BUFFER_SIZE, ConnectionGate, DnsResolver, ProxyProtocol, ShutdownToken,
+    SocketOptions, Throttle, Users,
+};
+
+/// Server options, grouping everything [`proxy_with_config`](crate::proxy_with_config)
+/// can be tuned with
+///
+/// Start from [`ServerConfig::default`] and set the fields of interest.
+#[derive(Clone, Debug)]
+pub struct ServerConfig<R = DnsResolver> {
+    /// Resolves domain names requested by clients, see
+    /// [`FallbackResolver`](socks5::address::FallbackResolver) to try several in order
+    pub resolver: R,
+    /// Relay bandwidth limits, unlimited by default
+    pub throttle: Throttle,
+    /// Size of the relay buffer of each direction, 16 KiB by default; larger
+    /// buffers favor throughput, smaller ones many concurrent connections
+    pub buffer_size: usize,
+    /// Stops relaying when triggered
+    pub shutdown: Option<ShutdownToken>,
+    /// Time given to in-flight relays to flush after shutdown
+    pub shutdown_grace: Duration,
+    /// Options for connections to destinations
+    pub outbound: SocketOptions,
+    /// Sends a PROXY protocol header carrying the client address to destinations
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Maximum time from the start of serving a client until its request header
+    /// is read, the authentication and the replies written meanwhile included;
+    /// bounds the resources held by clients which connect and then stall or
+    /// trickle bytes. Once expired, the connection ends with
+    /// [`ProxyOutcome::Timeout`](crate::ProxyOutcome::Timeout) without a reply.
+    pub handshake_timeout: Option<Duration>,
+    /// Ends a relay once nothing was read from either side for this long,
+    /// unlimited by default; bounds a connection which one side half-closed
+    /// while the other never does
+    pub idle_timeout: Option<Duration>,
+    /// Masks the client and destination addresses the server formats into errors,
+    /// see [`Address::redacted`](socks5::address::Address::redacted); the
+    /// returned [`ProxyOutcome`](crate::ProxyOutcome) is left for the caller to
+    /// print with [`redacted`](crate::ProxyOutcome::redacted)
+    pub redact_addresses: bool,
+    /// Caps the number of concurrent relays, unlimited by default
+    pub gate: Option<ConnectionGate>,
+    /// Requires clients to authenticate with a username and password
+    pub users: Option<Users>,
+    /// Ceilings on the messages read from clients and the characters accepted
+    /// in requested domain names; tighten them when exposed to hostile networks
+    pub limits: DecodeLimits,
+}
+
+impl<R> ServerConfig<R> {
+    /// Replaces the resolver
+    pub fn with_resolver<S: Resolver>(self, resolver: S) -> ServerConfig<S> {
+        ServerConfig {
+            resolver,
+            throttle: self.throttle,
+            buffer_size: self.buffer_size,
+            shutdown: self.shutdown,
+            shutdown_grace: self.shutdown_grace,
+            outbound: self.outbound,
+            proxy_protocol: self.proxy_protocol,
+            handshake_timeout: self.handshake_timeout,
+            idle_timeout: self.idle_timeout,
+            redact_addresses: self.redact_addresses,
+            gate: self.gate,
+            users: self.users,
+            limits: self.limits,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            resolver: DnsResolver,
+            throttle: Throttle::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            shutdown: None,
+            shutdown_grace: Duration::from_secs(5),
+            outbound: SocketOptions::default(),
+            proxy_protocol: None,
+            handshake_timeout: None,
+            idle_timeout: None,
+            redact_addresses: false,
+            gate: None,
+            users: None,
+            limits: DecodeLimits::default(),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Identifies one proxied connection, unique within the process
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub(crate) fn next() -> ConnectionId {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        ConnectionId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
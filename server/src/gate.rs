@@ -0,0 +1,118 @@
+use std::{
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_io::Timer;
+use event_listener::Event;
+use futures_lite::future::or;
+
+/// Cloneable cap on concurrent relays, shared by running proxies
+///
+/// A proxy takes a permit before connecting to the destination and holds it
+/// until the relay is done. Under overload new connections wait for a permit,
+/// or with a timeout are answered with
+/// [`Replies::ConnectionNotAllowed`](socks5::message::Replies::ConnectionNotAllowed)
+/// once it expires.
+#[derive(Clone)]
+pub struct ConnectionGate {
+    inner: Arc<Inner>,
+    timeout: Option<Duration>,
+}
+
+struct Inner {
+    available: AtomicUsize,
+    released: Event,
+}
+
+/// Permit of a [`ConnectionGate`], given back when dropped
+pub struct ConnectionPermit {
+    inner: Arc<Inner>,
+}
+
+impl ConnectionGate {
+    /// Lets at most `permits` relays run at once
+    pub fn new(permits: usize) -> ConnectionGate {
+        ConnectionGate {
+            inner: Arc::new(Inner {
+                available: AtomicUsize::new(permits),
+                released: Event::new(),
+            }),
+            timeout: None,
+        }
+    }
+
+    /// Gives up waiting for a permit after `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> ConnectionGate {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Number of permits currently available
+    pub fn available(&self) -> usize {
+        self.inner.available.load(Ordering::SeqCst)
+    }
+
+    /// Takes a permit if one is available, without waiting
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        self.inner
+            .available
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .ok()?;
+        Some(ConnectionPermit {
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// Waits for a permit, `None` if the timeout expired first
+    pub async fn acquire(&self) -> Option<ConnectionPermit> {
+        let wait = async {
+            loop {
+                if let Some(permit) = self.try_acquire() {
+                    return Some(permit);
+                }
+                let listener = self.inner.released.listen();
+                if let Some(permit) = self.try_acquire() {
+                    return Some(permit);
+                }
+                listener.await;
+            }
+        };
+        match self.timeout {
+            Some(timeout) => {
+                let expired = async {
+                    Timer::after(timeout).await;
+                    None
+                };
+                or(wait, expired).await
+            }
+            None => wait.await,
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.inner.available.fetch_add(1, Ordering::SeqCst);
+        self.inner.released.notify(1);
+    }
+}
+
+impl Debug for ConnectionGate {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("ConnectionGate")
+            .field("available", &self.available())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Debug for ConnectionPermit {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("ConnectionPermit").finish_non_exhaustive()
+    }
+}
@@ -1,102 +1,1021 @@
 use std::{
-    io::ErrorKind,
+    future::Future,
     net::{SocketAddr, TcpStream},
 };
 
-use anyhow::{anyhow, Result};
-use async_io::Async;
+use anyhow::{anyhow, bail, Context, Result};
+use async_io::{Async, Timer};
+use bytes::BytesMut;
 use futures_lite::{
-    future::race,
-    io::{copy, split},
-    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    future::{race, try_zip},
+    AsyncReadExt, AsyncWriteExt,
 };
 use socks5::{
-    head::{AuthenticationRequest, AuthenticationResponse, TcpRequestHeader},
-    message::{Command, Method, Replies},
-    ser::{Decode, Encode},
+    address::{Address, Resolver},
+    error::{Error, ErrorKind},
+    gssapi::{self, GssContext},
+    head::{
+        AuthenticationRequest, AuthenticationResponse, PasswordRequest, PasswordResponse,
+        Socks4Response, TcpRequestHeader, TcpResponseHeader,
+    },
+    message::{Command, Method, MethodSet, Replies},
+    ser::{read_limited, Encode},
 };
 
-pub async fn proxy<'a, T: AsyncReadExt + AsyncWriteExt + Unpin + 'a>(
+#[cfg(feature = "smol")]
+pub use crate::listener::serve;
+use crate::relay::{relay_with, HalfClose};
+pub use crate::{
+    config::ServerConfig,
+    connection_id::ConnectionId,
+    gate::{ConnectionGate, ConnectionPermit},
+    outcome::{HandshakeBytes, ProxyOutcome, Served},
+    proxy_protocol::ProxyProtocol,
+    rate_limit::{RateLimit, Throttle},
+    relay::relay,
+    resolver::{CachingResolver, DnsResolver, StaticResolver},
+    shutdown::ShutdownToken,
+    socket::{FamilyPreference, SocketOptions},
+    users::Users,
+};
+
+mod config;
+mod connection_id;
+mod gate;
+#[cfg(feature = "smol")]
+mod listener;
+mod outcome;
+mod proxy_protocol;
+mod rate_limit;
+mod relay;
+mod resolver;
+mod shutdown;
+mod socket;
+#[cfg(target_os = "linux")]
+mod splice;
+mod users;
+
+/// Serves one client connection
+///
+/// `src` is the address of the client, `None` for a client without one, such as
+/// one connected over a Unix domain socket.
+pub async fn proxy<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    connect: &mut T,
+    src: impl Into<Option<SocketAddr>>,
+) -> Result<Served> {
+    proxy_with_config(connect, src, &ServerConfig::default()).await
+}
+
+/// Serves one client connection, with the options of `config`
+///
+/// The [`ConnectionId`] assigned to the connection is returned along with the
+/// outcome. Errors carry it and the address of the client, if any, as context,
+/// print them with `{:#}` to include them.
+pub async fn proxy_with_config<T: AsyncReadExt + AsyncWriteExt + Unpin, R: Resolver>(
+    connect: &mut T,
+    src: impl Into<Option<SocketAddr>>,
+    config: &ServerConfig<R>,
+) -> Result<Served> {
+    let id = ConnectionId::next();
+    let src = src.into();
+    let mut handshake = HandshakeBytes::default();
+    let outcome = serve_connection(connect, src, config, None, &mut handshake)
+        .await
+        .with_context(|| error_context(id, src, config.redact_addresses))?;
+    Ok(Served {
+        id,
+        handshake,
+        outcome,
+    })
+}
+
+/// Performs the negotiation and reads the request header, leaving the request to
+/// the caller
+///
+/// The caller answers with a [`TcpResponseHeader`] written to `connect`,
+/// then uses the stream as it sees fit, such as routing it to an internal service.
+/// A malformed request header is answered before the error is returned, as is a
+/// rejected authentication.
+pub async fn accept<T: AsyncReadExt + AsyncWriteExt + Unpin, R>(
+    connect: &mut T,
+    config: &ServerConfig<R>,
+) -> Result<TcpRequestHeader> {
+    let mut buf = BytesMut::new();
+    let bytes = &mut HandshakeBytes::default();
+    match handshake_within(connect, config, None, &mut buf, bytes).await? {
+        Ok(header) => Ok(header),
+        Err(outcome) if config.redact_addresses => Err(anyhow!("{}", outcome.redacted())),
+        Err(outcome) => Err(anyhow!("{outcome}")),
+    }
+}
+
+/// Serves one client connection, which must authenticate with GSSAPI
+///
+/// `context` is the acceptor side of the security context, see [`socks5::gssapi`].
+pub async fn proxy_with_gssapi<T: AsyncReadExt + AsyncWriteExt + Unpin, R: Resolver>(
     connect: &mut T,
+    src: impl Into<Option<SocketAddr>>,
+    config: &ServerConfig<R>,
+    context: &mut dyn GssContext,
+) -> Result<Served> {
+    let id = ConnectionId::next();
+    let src = src.into();
+    let mut handshake = HandshakeBytes::default();
+    let outcome = serve_connection(connect, src, config, Some(context), &mut handshake)
+        .await
+        .with_context(|| error_context(id, src, config.redact_addresses))?;
+    Ok(Served {
+        id,
+        handshake,
+        outcome,
+    })
+}
+
+/// Same as [`proxy_with_config`], over a TCP stream
+///
+/// On Linux the relay moves the data between the sockets with `splice(2)`, without
+/// copying it through user space, unless a [`Throttle`] is configured.
+pub async fn proxy_tcp<R: Resolver>(
+    connect: &mut Async<TcpStream>,
     src: SocketAddr,
-) -> Result<()>
+    config: &ServerConfig<R>,
+) -> Result<Served> {
+    proxy_tcp_as(ConnectionId::next(), connect, src, config).await
+}
+
+/// Same as [`proxy_tcp`], for a connection already assigned `id`
+pub(crate) async fn proxy_tcp_as<R: Resolver>(
+    id: ConnectionId,
+    connect: &mut Async<TcpStream>,
+    src: SocketAddr,
+    config: &ServerConfig<R>,
+) -> Result<Served> {
+    let mut handshake = HandshakeBytes::default();
+    let serve = async {
+        match open(connect, Some(src), config, None, &mut handshake).await? {
+            Ok((dest_tcp, _permit)) => relay_tcp(connect, &dest_tcp, config).await,
+            Err(outcome) => Ok(outcome),
+        }
+    };
+    let outcome = serve
+        .await
+        .with_context(|| error_context(id, Some(src), config.redact_addresses))?;
+    Ok(Served {
+        id,
+        handshake,
+        outcome,
+    })
+}
+
+/// Same as [`proxy`], over a tokio stream
+#[cfg(feature = "tokio")]
+pub async fn proxy_tokio<T>(connect: &mut T, src: impl Into<Option<SocketAddr>>) -> Result<Served>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    proxy(&mut socks5::compat::TokioIo(connect), src).await
+}
+
+/// Same as [`proxy_with_config`], over a tokio stream
+#[cfg(feature = "tokio")]
+pub async fn proxy_with_config_tokio<T, R>(
+    connect: &mut T,
+    src: impl Into<Option<SocketAddr>>,
+    config: &ServerConfig<R>,
+) -> Result<Served>
 where
-    &'a T: AsyncRead + AsyncWrite,
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    R: Resolver,
 {
+    proxy_with_config(&mut socks5::compat::TokioIo(connect), src, config).await
+}
+
+/// Context of the errors of a connection, with the client address masked if
+/// `redact` is set
+fn error_context(id: ConnectionId, src: Option<SocketAddr>, redact: bool) -> String {
+    match src {
+        Some(src) if redact => format!("connection {id} from {}", Address::from(src).redacted()),
+        Some(src) => format!("connection {id} from {src}"),
+        None => format!("connection {id}"),
+    }
+}
+
+async fn serve_connection<T: AsyncReadExt + AsyncWriteExt + Unpin, R: Resolver>(
+    connect: &mut T,
+    src: Option<SocketAddr>,
+    config: &ServerConfig<R>,
+    gssapi: Option<&mut dyn GssContext>,
+    bytes: &mut HandshakeBytes,
+) -> Result<ProxyOutcome> {
+    match open(connect, src, config, gssapi, bytes).await? {
+        Ok((dest_tcp, _permit)) => relay_stream(connect, &dest_tcp, config).await,
+        Err(outcome) => Ok(outcome),
+    }
+}
+
+/// Serves a request up to the reply, returns the destination stream if it was
+/// connected, along with the permit of the gate to hold while relaying, or how
+/// the connection ended otherwise; the handshake is counted into `bytes`
+async fn open<T: AsyncReadExt + AsyncWriteExt + Unpin, R: Resolver>(
+    connect: &mut T,
+    src: Option<SocketAddr>,
+    config: &ServerConfig<R>,
+    gssapi: Option<&mut dyn GssContext>,
+    bytes: &mut HandshakeBytes,
+) -> Result<core::result::Result<(Async<TcpStream>, Option<ConnectionPermit>), ProxyOutcome>> {
+    // one buffer for all the messages sent to the client
+    let mut buf = BytesMut::new();
+    let buf = &mut buf;
+    let header = match handshake_within(connect, config, gssapi, buf, bytes).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(outcome)) => return Ok(Err(outcome)),
+        Err(e) => match error_kind(&e) {
+            // a client connecting and leaving without a word, such as a port scanner
+            Some(ErrorKind::Disconnected) => return Ok(Err(ProxyOutcome::Disconnected)),
+            Some(ErrorKind::Timeout) => return Ok(Err(ProxyOutcome::Timeout)),
+            _ => return Err(e),
+        },
+    };
+    let (command, addr) = header.into_parts();
+    match command {
+        Command::Connect => {
+            let permit = match &config.gate {
+                Some(gate) => match gate.acquire().await {
+                    Some(permit) => Some(permit),
+                    None => {
+                        let resp = TcpResponseHeader::failure(Replies::ConnectionNotAllowed);
+                        resp.write_to_reusing(connect, buf).await?;
+                        bytes.sent(&resp);
+                        return Ok(Err(ProxyOutcome::Denied));
+                    }
+                },
+                None => None,
+            };
+            let dest_addrs = match addr.to_socket_addrs(&config.resolver).await {
+                Ok(addrs) => addrs,
+                Err(error) => {
+                    let resp = TcpResponseHeader::failure(error.reply);
+                    resp.write_to_reusing(connect, buf).await?;
+                    bytes.sent(&resp);
+                    let address = addr;
+                    return Ok(Err(ProxyOutcome::ResolveFailed { address, error }));
+                }
+            };
+            let (mut dest_tcp, dest_addr) =
+                match socket::connect_any(dest_addrs, &config.outbound).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        let error = Error::from(e);
+                        let resp = TcpResponseHeader::failure(error.reply);
+                        resp.write_to_reusing(connect, buf).await?;
+                        bytes.sent(&resp);
+                        let address = addr;
+                        return Ok(Err(ProxyOutcome::ConnectFailed { address, error }));
+                    }
+                };
+            if let Some(version) = config.proxy_protocol {
+                dest_tcp.write_all(&version.header(src, dest_addr)).await?;
+            }
+            let resp = TcpResponseHeader::success(dest_addr.into());
+            resp.write_to_reusing(connect, buf).await?;
+            bytes.sent(&resp);
+            Ok(Ok((dest_tcp, permit)))
+        }
+        // Bind and UdpAssociate, is not supported
+        command => {
+            let rh = TcpResponseHeader::failure(Replies::CommandNotSupported);
+            rh.write_to_reusing(connect, buf).await?;
+            bytes.sent(&rh);
+            Ok(Err(ProxyOutcome::CommandNotSupported(command)))
+        }
+    }
+}
+
+/// Relays between the client and the destination until both are done
+async fn relay_stream<T: AsyncReadExt + AsyncWriteExt + Unpin, R>(
+    connect: &mut T,
+    dest_tcp: &Async<TcpStream>,
+    config: &ServerConfig<R>,
+) -> Result<ProxyOutcome> {
+    let shutdown = config.shutdown.as_ref();
+    let relay = relay_with(
+        connect,
+        HalfClose(dest_tcp),
+        config.buffer_size,
+        config.throttle,
+        shutdown,
+        config.idle_timeout,
+    );
+    with_grace(relay, config).await
+}
+
+/// Same as [`relay_stream`], with `splice(2)` on Linux unless throttled
+async fn relay_tcp<R>(
+    connect: &mut Async<TcpStream>,
+    dest_tcp: &Async<TcpStream>,
+    config: &ServerConfig<R>,
+) -> Result<ProxyOutcome> {
+    #[cfg(target_os = "linux")]
+    if config.throttle == Throttle::default() {
+        let shutdown = config.shutdown.as_ref();
+        let idle = config.idle_timeout.map(relay::Idle::new);
+        let relay = try_zip(
+            splice::splice(connect, dest_tcp, shutdown, idle.as_ref()),
+            splice::splice(dest_tcp, connect, shutdown, idle.as_ref()),
+        );
+        return with_grace(relay, config).await;
+    }
+    relay_stream(&mut HalfClose(connect), dest_tcp, config).await
+}
+
+/// Awaits `relay`, cut short once the grace period after a shutdown expired
+async fn with_grace<R>(
+    relay: impl Future<Output = std::io::Result<(u64, u64)>>,
+    config: &ServerConfig<R>,
+) -> Result<ProxyOutcome> {
+    let relay = async {
+        match relay.await {
+            Ok((up, down)) => Ok(ProxyOutcome::Completed { up, down }),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                Err(anyhow!("relay idle timeout expired"))
+            }
+            Err(_) => Err(anyhow!("io error")),
+        }
+    };
+    match &config.shutdown {
+        Some(token) => {
+            let expired = async {
+                token.wait().await;
+                Timer::after(config.shutdown_grace).await;
+                Err(anyhow!("shutdown grace period expired"))
+            };
+            race(relay, expired).await
+        }
+        None => relay.await,
+    }
+}
+
+/// Runs [`handshake`] within the handshake timeout of `config` if any, failing
+/// with a timeout error once it expires, whichever message is being read or written
+async fn handshake_within<C: AsyncReadExt + AsyncWriteExt + Unpin, R>(
+    connect: &mut C,
+    config: &ServerConfig<R>,
+    gssapi: Option<&mut dyn GssContext>,
+    buf: &mut BytesMut,
+    bytes: &mut HandshakeBytes,
+) -> Result<core::result::Result<TcpRequestHeader, ProxyOutcome>> {
+    let handshake = handshake(connect, config, gssapi, buf, bytes);
+    match config.handshake_timeout {
+        Some(timeout) => {
+            let expired = async {
+                Timer::after(timeout).await;
+                Err(Error::timeout().into())
+            };
+            race(handshake, expired).await
+        }
+        None => handshake.await,
+    }
+}
+
+/// Negotiates the authentication method and reads the request header, within
+/// the decode limits of `config`; the replies are encoded into `buf` and every
+/// message is counted into `bytes`
+async fn handshake<C: AsyncReadExt + AsyncWriteExt + Unpin, R>(
+    connect: &mut C,
+    config: &ServerConfig<R>,
+    gssapi: Option<&mut dyn GssContext>,
+    buf: &mut BytesMut,
+    bytes: &mut HandshakeBytes,
+) -> Result<core::result::Result<TcpRequestHeader, ProxyOutcome>> {
+    let users = config.users.as_ref();
+    let limits = &config.limits;
     // authentication
-    let authentication_request = AuthenticationRequest::read(connect).await?;
-    let authentication_response: AuthenticationResponse =
-        if authentication_request.required_authentication() {
-            Method::NotAcceptable
+    let authentication_request = match read_limited::<AuthenticationRequest, _>(connect, limits)
+        .await
+    {
+        Ok((v, n)) => {
+            bytes.read += n as u64;
+            v
+        }
+        Err(e) => match e.kind() {
+            ErrorKind::WrongVersion(b'A'..=b'Z') => bail!("client sent HTTP request to SOCKS port"),
+            ErrorKind::WrongVersion(Socks4Response::REQUEST_VERSION) => {
+                let resp = Socks4Response::rejected();
+                resp.write_to_reusing(connect, buf).await?;
+                bytes.sent(&resp);
+                bail!("client sent SOCKS4 request");
+            }
+            _ => return Err(e.into()),
+        },
+    };
+    let offered = authentication_request.method_set();
+    let mut preferences = MethodSet::new();
+    if gssapi.is_some() {
+        preferences.insert(Method::GSSAPI);
+    }
+    if users.is_some() {
+        preferences.insert(Method::PASSWORD);
+    }
+    if preferences.is_empty() {
+        preferences.insert(Method::NONE);
+    }
+    // sets iterate by value, so GSSAPI is preferred over PASSWORD
+    let method = offered
+        .intersection(&preferences)
+        .iter()
+        .next()
+        .unwrap_or(Method::NotAcceptable);
+    let resp = AuthenticationResponse::from(method);
+    resp.write_to_reusing(connect, buf).await?;
+    bytes.sent(&resp);
+    if method == Method::NotAcceptable {
+        return Ok(Err(ProxyOutcome::AuthRejected));
+    }
+    if let (Method::GSSAPI, Some(context)) = (method, gssapi) {
+        let (read, written) = gssapi::establish(context, connect, false).await?;
+        bytes.read += read as u64;
+        bytes.written += written as u64;
+    }
+    if let (Method::PASSWORD, Some(users)) = (method, users) {
+        let (request, n) = read_limited::<PasswordRequest, _>(connect, limits).await?;
+        bytes.read += n as u64;
+        let accepted = users.check(&request);
+        let response = if accepted {
+            PasswordResponse::success()
         } else {
-            Method::NONE
+            PasswordResponse::failure()
+        };
+        response.write_to_reusing(connect, buf).await?;
+        bytes.sent(&response);
+        if !accepted {
+            return Ok(Err(ProxyOutcome::AuthRejected));
         }
-        .into();
-    write(authentication_response, connect).await?;
+    }
 
     // requests
-    let header = match TcpRequestHeader::read(connect).await {
-        Ok(v) => v,
+    match read_limited::<TcpRequestHeader, _>(connect, limits).await {
+        Ok((v, n)) => {
+            bytes.read += n as u64;
+            Ok(Ok(v))
+        }
         Err(e) => {
-            let resp = e.reply.into_response(src.into());
-            write(resp, connect).await?;
-            return Err(e.into());
+            let resp = TcpResponseHeader::failure(e.reply);
+            resp.write_to_reusing(connect, buf).await?;
+            bytes.sent(&resp);
+            Err(e.into())
         }
+    }
+}
+
+/// Kind of the protocol error behind `e`, if any
+fn error_kind(e: &anyhow::Error) -> Option<ErrorKind> {
+    e.downcast_ref::<Error>().map(|e| e.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{Ipv4Addr, TcpListener},
+        time::Duration,
     };
-    let addr = header.address();
-    match header.command() {
-        Command::Connect => {
-            let dest_addr = match addr.lookup(lookup).await {
-                Ok(addr) => addr,
-                Err(e) => {
-                    let resp = e.reply.into_response(addr.clone());
-                    write(resp, connect).await?;
-                    return Err(e.into());
-                }
+
+    use futures_lite::future::{block_on, zip};
+    use socks5::{
+        address::DomainPolicy,
+        ser::{Decode, DecodeLimits},
+    };
+
+    use super::*;
+
+    /// Resolver knowing no name
+    struct NoSuchHost;
+
+    impl Resolver for NoSuchHost {
+        async fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    /// Connected pair of loopback TCP streams, the client first
+    fn pair() -> (Async<TcpStream>, Async<TcpStream>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Async::new(client).unwrap(), Async::new(server).unwrap())
+    }
+
+    /// Address nothing listens on
+    fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// Sends a CONNECT to `dest` without authentication, returns the reply
+    async fn connect_to(client: &mut Async<TcpStream>, dest: &Address) -> TcpResponseHeader {
+        AuthenticationRequest::new([Method::NONE])
+            .write_to(client)
+            .await
+            .unwrap();
+        let resp = AuthenticationResponse::read(client).await.unwrap();
+        assert_eq!(resp.method(), Method::NONE);
+        TcpRequestHeader::connect(dest.clone())
+            .write_to(client)
+            .await
+            .unwrap();
+        TcpResponseHeader::read(client).await.unwrap()
+    }
+
+    #[test]
+    fn resolve_failure_carries_the_address_and_error() {
+        let (mut client, mut server) = pair();
+        let config = ServerConfig::default().with_resolver(NoSuchHost);
+        let dest = Address::from_domain("nowhere.invalid", 80).unwrap();
+        let (outcome, resp) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            connect_to(&mut client, &dest),
+        ));
+        assert_eq!(resp.reply, Replies::HostUnreachable);
+        match outcome.unwrap().outcome {
+            ProxyOutcome::ResolveFailed { address, error } => {
+                assert_eq!(address, dest);
+                assert_eq!(error.reply, Replies::HostUnreachable);
+            }
+            outcome => panic!("unexpected outcome {outcome}"),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_domain_is_rejected() {
+        let (mut client, mut server) = pair();
+        let dest = Address::try_from((&b"exa\xc3\x28mple.com"[..], 80)).unwrap();
+        let (outcome, resp) = block_on(zip(
+            proxy(&mut server, None),
+            connect_to(&mut client, &dest),
+        ));
+        assert_eq!(resp.reply, Replies::AddressTypeNotSupported);
+        match outcome.unwrap().outcome {
+            ProxyOutcome::ResolveFailed { address, error } => {
+                assert_eq!(address, dest);
+                assert_eq!(error.kind(), ErrorKind::InvalidAddress);
+            }
+            outcome => panic!("unexpected outcome {outcome}"),
+        }
+    }
+
+    #[test]
+    fn connect_failure_carries_the_address_and_error() {
+        let (mut client, mut server) = pair();
+        let dest = Address::from(closed_port());
+        let (outcome, resp) = block_on(zip(
+            proxy(&mut server, None),
+            connect_to(&mut client, &dest),
+        ));
+        assert_eq!(resp.reply, Replies::ConnectionRefused);
+        match outcome.unwrap().outcome {
+            ProxyOutcome::ConnectFailed { address, error } => {
+                assert_eq!(address, dest);
+                assert_eq!(error.reply, Replies::ConnectionRefused);
+                assert_eq!(error.kind(), ErrorKind::Io);
+            }
+            outcome => panic!("unexpected outcome {outcome}"),
+        }
+    }
+
+    #[test]
+    fn error_context_masks_the_client_address() {
+        let id = ConnectionId::next();
+        let src = SocketAddr::from(([203, 0, 113, 7], 40000));
+        let context = error_context(id, Some(src), false);
+        assert_eq!(context, format!("connection {id} from 203.0.113.7:40000"));
+        let context = error_context(id, Some(src), true);
+        assert_eq!(context, format!("connection {id} from 203.0.113.xxx:40000"));
+    }
+
+    #[test]
+    fn redacted_outcome_masks_the_destination() {
+        let outcome = ProxyOutcome::ConnectFailed {
+            address: Address::from_domain("www.example.com", 443).unwrap(),
+            error: Error::from(io::Error::from(io::ErrorKind::ConnectionRefused)),
+        };
+        let shown = outcome.to_string();
+        assert!(shown.starts_with("connect to www.example.com:443 failed: "));
+        let redacted = outcome.redacted().to_string();
+        assert!(redacted.starts_with("connect to ww…le.com:443 failed: "));
+    }
+
+    #[test]
+    fn handshake_timeout_covers_the_whole_handshake() {
+        let (mut client, mut server) = pair();
+        let config = ServerConfig {
+            handshake_timeout: Some(Duration::from_millis(200)),
+            ..ServerConfig::default()
+        };
+        // each message arrives well within the timeout, the whole handshake not
+        let trickle = async {
+            Timer::after(Duration::from_millis(120)).await;
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+            Timer::after(Duration::from_millis(120)).await;
+            let request = [5, 1, 0, 1, 127, 0, 0, 1, 0, 80];
+            let _ = client.write_all(&request).await;
+        };
+        let (served, ()) = block_on(zip(proxy_with_config(&mut server, None, &config), trickle));
+        assert_eq!(served.unwrap().outcome, ProxyOutcome::Timeout);
+    }
+
+    #[test]
+    fn handshake_error_carries_the_client_address() {
+        for redact_addresses in [false, true] {
+            let (mut client, mut server) = pair();
+            let src = client.get_ref().local_addr().unwrap();
+            let config = ServerConfig {
+                redact_addresses,
+                ..ServerConfig::default()
             };
-            let dest_tcp = match Async::<TcpStream>::connect(dest_addr).await {
-                Ok(s) => {
-                    reply(Replies::Succeeded, dest_addr, connect).await?;
-                    s
-                }
-                Err(e) => return Err(e.into()),
+            let http = client.write_all(b"GET / HTTP/1.1\r\n\r\n");
+            let (served, sent) = block_on(zip(proxy_tcp(&mut server, src, &config), http));
+            sent.unwrap();
+            let error = format!("{:#}", served.unwrap_err());
+            let shown = match redact_addresses {
+                true => Address::from(src).redacted().to_string(),
+                false => src.to_string(),
             };
+            assert!(error.contains(&format!(" from {shown}: ")), "{error}");
+            assert_eq!(error.contains(&src.to_string()), !redact_addresses);
+        }
+    }
+
+    /// Sends a CONNECT to `dest` without authentication, returns the raw reply
+    async fn raw_reply(client: &mut Async<TcpStream>, dest: &Address) -> Vec<u8> {
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut [0; 2]).await.unwrap();
+        let request = TcpRequestHeader::connect(dest.clone()).as_bytes();
+        client.write_all(&request).await.unwrap();
+        let mut reply = vec![0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[test]
+    fn reply_bytes_tell_refused_from_unreachable() {
+        let (mut client, mut server) = pair();
+        let dest = Address::from(closed_port());
+        let (_, reply) = block_on(zip(proxy(&mut server, None), raw_reply(&mut client, &dest)));
+        assert_eq!(reply, [5, 0x05, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let (mut client, mut server) = pair();
+        let config = ServerConfig::default().with_resolver(NoSuchHost);
+        let dest = Address::from_domain("nowhere.invalid", 80).unwrap();
+        let (_, reply) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            raw_reply(&mut client, &dest),
+        ));
+        assert_eq!(reply, [5, 0x04, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
 
-            let (r, w) = split(connect);
-            race(copy(r, &dest_tcp), copy(&dest_tcp, w))
+    #[test]
+    fn unknown_command_is_answered_command_not_supported() {
+        let (mut client, mut server) = pair();
+        let client_side = async {
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+            client
+                .write_all(&[5, 0x04, 0, 1, 127, 0, 0, 1, 0, 80])
                 .await
-                .map(|_| ())
-                .map_err(|_| anyhow!("io error"))
+                .unwrap();
+            let mut reply = [0; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            reply
+        };
+        let (served, reply) = block_on(zip(proxy(&mut server, None), client_side));
+        assert!(served.is_err());
+        assert_eq!(reply[..2], [5, 0x07]);
+    }
+
+    #[test]
+    fn half_closed_client_still_gets_the_response() {
+        let dest = Async::<TcpListener>::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let dest_addr = Address::from(dest.get_ref().local_addr().unwrap());
+        let (mut client, mut server) = pair();
+        let src = client.get_ref().local_addr().unwrap();
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(5)),
+            ..ServerConfig::default()
+        };
+        let client_side = async {
+            let reply = connect_to(&mut client, &dest_addr).await;
+            assert_eq!(reply.reply, Replies::Succeeded);
+            client.write_all(b"request").await.unwrap();
+            client
+                .get_ref()
+                .shutdown(std::net::Shutdown::Write)
+                .unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        let dest_side = async {
+            let (mut stream, _) = dest.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            stream.write_all(b"late response").await.unwrap();
+        };
+        let (served, (response, ())) = block_on(zip(
+            proxy_tcp(&mut server, src, &config),
+            zip(client_side, dest_side),
+        ));
+        assert_eq!(response, b"late response");
+        let served = served.unwrap();
+        assert_eq!(served.outcome, ProxyOutcome::Completed { up: 7, down: 13 });
+        // 3 + 10 bytes of requests, 2 + 10 of replies, the relayed ones apart
+        let handshake = HandshakeBytes {
+            read: 13,
+            written: 12,
+        };
+        assert_eq!(served.handshake, handshake);
+    }
+
+    #[test]
+    fn handshake_bytes_are_counted() {
+        let (mut client, mut server) = pair();
+        let config = ServerConfig {
+            users: Some(Users::new().with_user(b"alice", b"secret")),
+            ..ServerConfig::default()
+        };
+        let dest = Address::from(closed_port());
+        let client_side = async {
+            client.write_all(&[5, 2, 0, 2]).await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+            let password = PasswordRequest::new(b"alice", b"secret").unwrap();
+            password.write_to(&mut client).await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+            let request = TcpRequestHeader::connect(dest.clone());
+            request.write_to(&mut client).await.unwrap();
+            TcpResponseHeader::read(&mut client).await.unwrap()
+        };
+        let (served, reply) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            client_side,
+        ));
+        assert_eq!(reply.reply, Replies::ConnectionRefused);
+        let served = served.unwrap();
+        assert!(matches!(served.outcome, ProxyOutcome::ConnectFailed { .. }));
+        // requests of 4, 1 + 1 + 5 + 1 + 6 and 10 bytes, replies of 2, 2 and 10
+        let handshake = HandshakeBytes {
+            read: 28,
+            written: 14,
+        };
+        assert_eq!(served.handshake, handshake);
+    }
+
+    /// GSSAPI context returning the tokens of its script in turn, established
+    /// once they are all returned, failing at step `fail_at` if set
+    struct Scripted {
+        script: Vec<Option<Vec<u8>>>,
+        fail_at: Option<usize>,
+        steps: usize,
+    }
+
+    impl Scripted {
+        fn new(script: &[Option<&[u8]>]) -> Scripted {
+            Scripted {
+                script: script
+                    .iter()
+                    .map(|token| token.map(<[u8]>::to_vec))
+                    .collect(),
+                fail_at: None,
+                steps: 0,
+            }
         }
-        // Bind and UdpAssociate, is not supported
-        _ => {
-            let rh = Replies::CommandNotSupported.into_response(addr.clone());
-            write(rh, connect).await
+    }
+
+    impl GssContext for Scripted {
+        fn step(&mut self, _token: &[u8]) -> socks5::error::Result<Option<Vec<u8>>> {
+            self.steps += 1;
+            if self.fail_at == Some(self.steps) {
+                return Err(Error::new(Replies::GeneralFailure, "defective credentials"));
+            }
+            Ok(self.script.remove(0))
+        }
+
+        fn is_established(&self) -> bool {
+            self.script.is_empty()
         }
     }
-}
 
-async fn write<T: Encode, C: AsyncWriteExt + Unpin>(head: T, c: &mut C) -> Result<()> {
-    c.write_all(&head.as_bytes()).await?;
-    c.flush().await?;
-    Ok(())
-}
+    #[test]
+    fn gssapi_client_is_served() {
+        let (mut client, mut server) = pair();
+        let config = ServerConfig::default();
+        let dest = Address::from(closed_port());
+        let mut acceptor = Scripted::new(&[Some(b"accept")]);
+        let mut initiator = Scripted::new(&[Some(b"init"), None]);
+        let (served, connected) = block_on(zip(
+            proxy_with_gssapi(&mut server, None, &config, &mut acceptor),
+            socks5_client::connect_with_gssapi(&mut client, dest, &mut initiator),
+        ));
+        // authenticated, then the destination refused the connection
+        let error = connected.unwrap_err();
+        assert_eq!(error.reply(), Some(Replies::ConnectionRefused));
+        assert!(initiator.is_established() && acceptor.is_established());
+        let served = served.unwrap();
+        assert!(matches!(served.outcome, ProxyOutcome::ConnectFailed { .. }));
+        // the token messages have a header of 4 bytes
+        let handshake = HandshakeBytes {
+            read: 3 + (4 + 4) + 10,
+            written: 2 + (4 + 6) + 10,
+        };
+        assert_eq!(served.handshake, handshake);
+    }
 
-async fn reply<C: AsyncWriteExt + Unpin>(
-    reply: Replies,
-    addr: SocketAddr,
-    c: &mut C,
-) -> Result<()> {
-    let header = reply.into_response(addr.into());
-    write(header, c).await
-}
+    #[test]
+    fn gssapi_failure_aborts_the_client() {
+        let (mut client, mut server) = pair();
+        let config = ServerConfig::default();
+        let dest = Address::from(closed_port());
+        let mut acceptor = Scripted::new(&[Some(b"accept")]);
+        acceptor.fail_at = Some(1);
+        let mut initiator = Scripted::new(&[Some(b"init"), None]);
+        let (served, connected) = block_on(zip(
+            proxy_with_gssapi(&mut server, None, &config, &mut acceptor),
+            socks5_client::connect_with_gssapi(&mut client, dest, &mut initiator),
+        ));
+        let error = format!("{:#}", served.unwrap_err());
+        assert!(error.ends_with("defective credentials"), "{error}");
+        match connected.unwrap_err() {
+            socks5_client::Error::Handshake(e) => {
+                assert_eq!(e.reply, Replies::ConnectionNotAllowed);
+            }
+            e => panic!("unexpected error {e}"),
+        }
+    }
 
-async fn lookup(name: &[u8], port: u16) -> std::io::Result<SocketAddr> {
-    let name = String::from_utf8_lossy(name);
-    let addrs = async_dns::lookup(&name).await?;
-    let addr = match addrs.into_iter().next() {
-        Some(addr) => addr.ip_address,
-        None => return Err(ErrorKind::AddrNotAvailable.into()),
-    };
-    Ok((addr, port).into())
+    #[test]
+    fn decode_limits_apply_to_clients() {
+        let (mut client, mut server) = pair();
+        let mut limits = DecodeLimits {
+            max_domain_len: 8,
+            ..DecodeLimits::default()
+        };
+        let config = ServerConfig {
+            limits,
+            ..ServerConfig::default()
+        };
+        let dest = Address::from_domain("www.example.com", 80).unwrap();
+        let (served, reply) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            raw_reply(&mut client, &dest),
+        ));
+        assert_eq!(reply[..2], [5, Replies::GeneralFailure.as_u8()]);
+        let error = served.unwrap_err();
+        assert_eq!(error_kind(&error), Some(ErrorKind::LimitExceeded));
+
+        // control characters are only accepted once the policy allows them
+        let dest = Address::try_from((&b"exa\x01mple"[..], 80)).unwrap();
+        limits.max_domain_len = DecodeLimits::default().max_domain_len;
+        for (policy, accepted) in [
+            (DomainPolicy::Standard, false),
+            (DomainPolicy::Permissive, true),
+        ] {
+            limits.domain_policy = policy;
+            let config = ServerConfig {
+                limits,
+                ..ServerConfig::default()
+            }
+            .with_resolver(NoSuchHost);
+            let (mut client, mut server) = pair();
+            let (served, reply) = block_on(zip(
+                proxy_with_config(&mut server, None, &config),
+                raw_reply(&mut client, &dest),
+            ));
+            // past the decoder, the name is left to the resolver
+            let expected = match accepted {
+                true => Replies::HostUnreachable,
+                false => Replies::AddressTypeNotSupported,
+            };
+            assert_eq!(reply[1], expected.as_u8(), "{policy:?}");
+            assert_eq!(served.is_ok(), accepted);
+        }
+    }
+
+    #[test]
+    fn gate_permit_is_given_back_on_failures() {
+        let gate = ConnectionGate::new(1);
+        let config = ServerConfig {
+            gate: Some(gate.clone()),
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..ServerConfig::default()
+        }
+        .with_resolver(NoSuchHost);
+
+        let (mut client, mut server) = pair();
+        let dest = Address::from_domain("nowhere.invalid", 80).unwrap();
+        let (served, resp) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            connect_to(&mut client, &dest),
+        ));
+        assert_eq!(resp.reply, Replies::HostUnreachable);
+        assert!(served.is_ok());
+        assert_eq!(gate.available(), 1);
+
+        let (mut client, mut server) = pair();
+        let dest = Address::from(closed_port());
+        let (served, resp) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            connect_to(&mut client, &dest),
+        ));
+        assert_eq!(resp.reply, Replies::ConnectionRefused);
+        assert!(served.is_ok());
+        assert_eq!(gate.available(), 1);
+
+        // a destination never answering, the relay ends with the idle timeout
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let dest = Address::from(listener.local_addr().unwrap());
+        let (mut client, mut server) = pair();
+        let connected = async {
+            let resp = connect_to(&mut client, &dest).await;
+            assert_eq!(resp.reply, Replies::Succeeded);
+            assert_eq!(gate.available(), 0);
+        };
+        let (served, ()) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            connected,
+        ));
+        assert!(served.is_err());
+        assert_eq!(gate.available(), 1);
+    }
+
+    #[test]
+    fn gate_timeout_is_answered_connection_not_allowed() {
+        let gate = ConnectionGate::new(1).with_timeout(Duration::from_millis(50));
+        let _held = gate.try_acquire().unwrap();
+        let config = ServerConfig {
+            gate: Some(gate.clone()),
+            ..ServerConfig::default()
+        };
+        let (mut client, mut server) = pair();
+        let dest = Address::from(closed_port());
+        let (served, resp) = block_on(zip(
+            proxy_with_config(&mut server, None, &config),
+            connect_to(&mut client, &dest),
+        ));
+        assert_eq!(resp.reply, Replies::ConnectionNotAllowed);
+        assert_eq!(served.unwrap().outcome, ProxyOutcome::Denied);
+        assert_eq!(gate.available(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn proxies_over_a_tokio_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let dest = Address::from(listener.local_addr().unwrap());
+        let destination = std::thread::spawn(move || {
+            use std::io::Write;
+
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let client_side = async {
+            let mut io = socks5::compat::TokioIo(&mut client);
+            socks5_client::connect_without_auth(&mut io, dest.clone())
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            client.shutdown().await.unwrap();
+            received
+        };
+        let (served, received) = runtime.block_on(zip(proxy_tokio(&mut server, None), client_side));
+        destination.join().unwrap();
+        assert_eq!(received, b"hello");
+        assert!(matches!(
+            served.unwrap().outcome,
+            ProxyOutcome::Completed { up: 0, down: 5 }
+        ));
+
+        let config = ServerConfig::default().with_resolver(NoSuchHost);
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let dest = Address::from_domain("nowhere.invalid", 80).unwrap();
+        let client_side = async {
+            let mut io = socks5::compat::TokioIo(&mut client);
+            socks5_client::connect_without_auth(&mut io, dest).await
+        };
+        let (served, connected) = runtime.block_on(zip(
+            proxy_with_config_tokio(&mut server, None, &config),
+            client_side,
+        ));
+        assert!(connected.is_err());
+        assert!(matches!(
+            served.unwrap().outcome,
+            ProxyOutcome::ResolveFailed { .. }
+        ));
+    }
 }
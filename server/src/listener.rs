@@ -0,0 +1,199 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_executor::{LocalExecutor, Task};
+use async_io::{Async, Timer};
+use futures_lite::future::race;
+use socks5::address::Resolver;
+
+use crate::{proxy_tcp_as, ConnectionId, Served, ServerConfig};
+
+/// Accepts clients on `listener` and serves each one like
+/// [`proxy_tcp`](crate::proxy_tcp) in its own task, all on the current thread
+///
+/// Clients are accepted regardless of the [`ConnectionGate`](crate::ConnectionGate)
+/// of `config`, those over its limit are answered with a failure reply.
+/// `observe` is called with the id and the address of each client once its
+/// connection ended, the errors carry them as context too.
+///
+/// Accepting goes on after errors concerning a single client, such as one
+/// resetting its connection before it is accepted, and after a pause once out
+/// of file descriptors or memory. Any other error of accepting is returned, as
+/// is `Ok` once the shutdown token of `config` is triggered, both after the
+/// running connections are done.
+pub async fn serve<R, F>(
+    listener: Async<TcpListener>,
+    config: ServerConfig<R>,
+    observe: F,
+) -> Result<()>
+where
+    R: Resolver + 'static,
+    F: Fn(ConnectionId, SocketAddr, Result<Served>) + 'static,
+{
+    let executor = LocalExecutor::new();
+    let config = Rc::new(config);
+    let observe = Rc::new(observe);
+    let serving = async {
+        let mut tasks: Vec<Task<()>> = Vec::new();
+        let mut result = Ok(());
+        while let Some(accepted) = accept(&listener, &config).await {
+            let (mut stream, src) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient(&e) => continue,
+                Err(e) if is_exhausted(&e) => {
+                    // running connections may release some by then
+                    Timer::after(EXHAUSTED_PAUSE).await;
+                    continue;
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            let config = config.clone();
+            let observe = observe.clone();
+            tasks.retain(|task| !task.is_finished());
+            tasks.push(executor.spawn(async move {
+                let id = ConnectionId::next();
+                let result = proxy_tcp_as(id, &mut stream, src, &config).await;
+                observe(id, src, result);
+            }));
+        }
+        for task in tasks {
+            task.await;
+        }
+        result
+    };
+    executor.run(serving).await
+}
+
+/// Pause before accepting again once out of resources
+const EXHAUSTED_PAUSE: Duration = Duration::from_millis(100);
+
+/// Whether accepting failed because of the client only
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Whether accepting failed for lack of file descriptors or memory
+fn is_exhausted(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM].contains(&code);
+    }
+    e.kind() == io::ErrorKind::OutOfMemory
+}
+
+/// Accepts the next client, `None` once the shutdown is triggered
+async fn accept<R>(
+    listener: &Async<TcpListener>,
+    config: &ServerConfig<R>,
+) -> Option<io::Result<(Async<TcpStream>, SocketAddr)>> {
+    let accepted = async { Some(listener.accept().await) };
+    match &config.shutdown {
+        Some(token) => {
+            let shutdown = async {
+                token.wait().await;
+                None
+            };
+            race(shutdown, accepted).await
+        }
+        None => accepted.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, net::Ipv4Addr};
+
+    use futures_lite::{
+        future::{block_on, zip},
+        AsyncWriteExt,
+    };
+
+    use super::*;
+    use crate::ShutdownToken;
+
+    #[test]
+    fn observe_gets_the_connection_id() {
+        let listener = Async::<TcpListener>::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        let token = ShutdownToken::new();
+        let config = ServerConfig {
+            shutdown: Some(token.clone()),
+            ..ServerConfig::default()
+        };
+        let observed = Rc::new(RefCell::new(Vec::new()));
+        let observe = {
+            let observed = observed.clone();
+            move |id: ConnectionId, src, result: Result<Served>| {
+                let error = format!("{:#}", result.unwrap_err());
+                observed.borrow_mut().push((id, src, error));
+                token.shutdown();
+            }
+        };
+        let client = async {
+            let mut client = Async::<TcpStream>::connect(addr).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            client.get_ref().local_addr().unwrap()
+        };
+        let (served, src) = block_on(zip(serve(listener, config, observe), client));
+        served.unwrap();
+        let observed = observed.borrow();
+        let (id, observed_src, error) = &observed[0];
+        assert_eq!(*observed_src, src);
+        assert!(error.starts_with(&format!("connection {id} from {src}: ")));
+    }
+
+    #[test]
+    fn classifies_accept_errors() {
+        assert!(is_transient(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(!is_transient(&io::ErrorKind::InvalidInput.into()));
+        #[cfg(unix)]
+        assert!(is_exhausted(&io::Error::from_raw_os_error(libc::EMFILE)));
+        #[cfg(unix)]
+        assert!(!is_exhausted(&io::Error::from_raw_os_error(libc::EINVAL)));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn accept_failure_waits_for_running_connections() {
+        use std::os::fd::AsRawFd;
+
+        use futures_lite::AsyncReadExt;
+
+        let listener = Async::<TcpListener>::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let observed = Rc::new(RefCell::new(0));
+        let observe = {
+            let observed = observed.clone();
+            move |_, _, _| *observed.borrow_mut() += 1
+        };
+        let client = async {
+            let mut client = Async::<TcpStream>::connect(addr).await.unwrap();
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            client.read_exact(&mut [0; 2]).await.unwrap();
+            // accepting fails with EINVAL from now on
+            assert_eq!(unsafe { libc::shutdown(fd, libc::SHUT_RD) }, 0);
+            Timer::after(Duration::from_millis(50)).await;
+            assert_eq!(*observed.borrow(), 0);
+        };
+        let (served, ()) = block_on(zip(
+            serve(listener, ServerConfig::default(), observe),
+            client,
+        ));
+        assert!(served.is_err());
+        assert_eq!(*observed.borrow(), 1);
+    }
+}
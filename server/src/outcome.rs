@@ -0,0 +1,115 @@
+use std::fmt::{Display, Formatter};
+
+use socks5::{address::Address, error::Error, message::Command, ser::Encode};
+
+use crate::ConnectionId;
+
+/// Result of a served connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Served {
+    /// Id assigned to the connection, which its errors are tagged with
+    pub id: ConnectionId,
+    /// Bytes of the handshake, apart from the relayed ones of
+    /// [`ProxyOutcome::Completed`]
+    pub handshake: HandshakeBytes,
+    /// How the connection ended
+    pub outcome: ProxyOutcome,
+}
+
+/// Bytes of the handshake messages exchanged with a client, versions included
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeBytes {
+    /// Read from the client: the authentication request, the authentication
+    /// subnegotiation and the request header
+    pub read: u64,
+    /// Written to the client: the replies
+    pub written: u64,
+}
+
+impl HandshakeBytes {
+    /// Counts a message written to the client
+    pub(crate) fn sent<M: Encode>(&mut self, message: &M) {
+        self.written += message.encoded_len() as u64 + 1;
+    }
+}
+
+impl Display for Served {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "connection {}: {}", self.id, self.outcome)
+    }
+}
+
+/// How a connection ended, when it ended in a way the protocol provides for
+///
+/// Errors of the proxies are left for unexpected failures, such as an I/O error
+/// or a malformed message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProxyOutcome {
+    /// The relay is done, after copying `up` bytes from the client to the
+    /// destination and `down` bytes back
+    Completed { up: u64, down: u64 },
+    /// The client left before sending anything
+    Disconnected,
+    /// The client offered no acceptable method, or failed to authenticate
+    AuthRejected,
+    /// The connection limit of the [`ConnectionGate`](crate::ConnectionGate) was reached
+    Denied,
+    /// The destination name could not be resolved, answered with the reply of
+    /// `error`
+    ResolveFailed { address: Address, error: Error },
+    /// Connecting to the destination failed, answered with the reply of `error`
+    ConnectFailed { address: Address, error: Error },
+    /// The client did not complete the handshake in time, see
+    /// [`ServerConfig::handshake_timeout`](crate::ServerConfig::handshake_timeout)
+    Timeout,
+    /// The command of the request is not supported
+    CommandNotSupported(Command),
+}
+
+impl ProxyOutcome {
+    /// Displays the outcome with the destination address masked, see
+    /// [`Address::redacted`]
+    pub fn redacted(&self) -> impl Display + '_ {
+        Redacted(self)
+    }
+
+    fn fmt_with(&self, f: &mut Formatter, redact: bool) -> std::fmt::Result {
+        let address = |address: &Address| match redact {
+            true => address.redacted().to_string(),
+            false => address.to_string(),
+        };
+        match self {
+            ProxyOutcome::Completed { up, down } => {
+                write!(f, "completed, {up} bytes up and {down} bytes down")
+            }
+            ProxyOutcome::Disconnected => f.write_str("client disconnected"),
+            ProxyOutcome::AuthRejected => f.write_str("authentication rejected"),
+            ProxyOutcome::Denied => f.write_str("connection limit reached"),
+            ProxyOutcome::ResolveFailed { address: a, error } => {
+                write!(f, "resolving {} failed: {}", address(a), error.message())
+            }
+            ProxyOutcome::ConnectFailed { address: a, error } => {
+                write!(f, "connect to {} failed: {}", address(a), error.message())
+            }
+            ProxyOutcome::Timeout => f.write_str("handshake timed out"),
+            ProxyOutcome::CommandNotSupported(command) => {
+                write!(f, "command {command} not supported")
+            }
+        }
+    }
+}
+
+impl Display for ProxyOutcome {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        self.fmt_with(f, false)
+    }
+}
+
+struct Redacted<'a>(&'a ProxyOutcome);
+
+impl Display for Redacted<'_> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        self.0.fmt_with(f, true)
+    }
+}
@@ -0,0 +1,175 @@
+use std::net::{IpAddr, SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+
+/// HAProxy PROXY protocol version, the header carries the client address to the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+impl ProxyProtocol {
+    /// Encodes the header for a connection from `src` to `dest`
+    ///
+    /// If the addresses are of different families, the IPv4 one is sent as an
+    /// IPv4-mapped IPv6 address. Without a client address, such as for a client
+    /// connected over a Unix domain socket, the header carries no addresses.
+    pub(crate) fn header(self, src: Option<SocketAddr>, dest: SocketAddr) -> BytesMut {
+        let Some(src) = src else {
+            return self.unknown();
+        };
+        let (src_ip, dest_ip) = match (src.ip(), dest.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+            (s, d) => (IpAddr::V6(to_v6(s)), IpAddr::V6(to_v6(d))),
+        };
+        let mut buffer = BytesMut::new();
+        match self {
+            ProxyProtocol::V1 => {
+                let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+                let line = format!(
+                    "PROXY {family} {src_ip} {dest_ip} {} {}\r\n",
+                    src.port(),
+                    dest.port()
+                );
+                buffer.put_slice(line.as_bytes());
+            }
+            ProxyProtocol::V2 => {
+                buffer.put_slice(&V2_SIGNATURE);
+                // version 2, PROXY command
+                buffer.put_u8(0x21);
+                match (src_ip, dest_ip) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        // TCP over IPv4
+                        buffer.put_u8(0x11);
+                        buffer.put_u16(12);
+                        buffer.put_slice(&s.octets());
+                        buffer.put_slice(&d.octets());
+                    }
+                    (s, d) => {
+                        // TCP over IPv6
+                        buffer.put_u8(0x21);
+                        buffer.put_u16(36);
+                        buffer.put_slice(&to_v6(s).octets());
+                        buffer.put_slice(&to_v6(d).octets());
+                    }
+                }
+                buffer.put_u16(src.port());
+                buffer.put_u16(dest.port());
+            }
+        }
+        buffer
+    }
+
+    /// Header of a connection whose addresses are unknown
+    fn unknown(self) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        match self {
+            ProxyProtocol::V1 => buffer.put_slice(b"PROXY UNKNOWN\r\n"),
+            ProxyProtocol::V2 => {
+                buffer.put_slice(&V2_SIGNATURE);
+                // version 2, LOCAL command, unspecified family and no addresses
+                buffer.put_u8(0x20);
+                buffer.put_u8(0x00);
+                buffer.put_u16(0);
+            }
+        }
+        buffer
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4() -> (SocketAddr, SocketAddr) {
+        let src = "192.0.2.1:56324".parse().unwrap();
+        let dest = "198.51.100.7:443".parse().unwrap();
+        (src, dest)
+    }
+
+    fn v6() -> (SocketAddr, SocketAddr) {
+        let src = "[2001:db8::1]:56324".parse().unwrap();
+        let dest = "[2001:db8::7]:443".parse().unwrap();
+        (src, dest)
+    }
+
+    #[test]
+    fn v1_headers() {
+        let (src, dest) = v4();
+        let header = ProxyProtocol::V1.header(Some(src), dest);
+        assert_eq!(
+            header,
+            &b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n"[..]
+        );
+        let (src, dest) = v6();
+        let header = ProxyProtocol::V1.header(Some(src), dest);
+        assert_eq!(
+            header,
+            &b"PROXY TCP6 2001:db8::1 2001:db8::7 56324 443\r\n"[..]
+        );
+        // mixed families are sent as IPv6
+        let header = ProxyProtocol::V1.header(Some(v4().0), dest);
+        let line = b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::7 56324 443\r\n";
+        assert_eq!(header, &line[..]);
+        let header = ProxyProtocol::V1.header(None, dest);
+        assert_eq!(header, &b"PROXY UNKNOWN\r\n"[..]);
+    }
+
+    #[test]
+    fn v2_header_over_ipv4() {
+        let (src, dest) = v4();
+        let header = ProxyProtocol::V2.header(Some(src), dest);
+        #[rustfmt::skip]
+        let expected = [
+            0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+            // version 2 PROXY, TCP over IPv4, 12 bytes of addresses
+            0x21, 0x11, 0x00, 0x0c,
+            192, 0, 2, 1,
+            198, 51, 100, 7,
+            0xdc, 0x04,
+            0x01, 0xbb,
+        ];
+        assert_eq!(header, &expected[..]);
+    }
+
+    #[test]
+    fn v2_header_over_ipv6() {
+        let (src, dest) = v6();
+        let header = ProxyProtocol::V2.header(Some(src), dest);
+        #[rustfmt::skip]
+        let expected = [
+            0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+            // version 2 PROXY, TCP over IPv6, 36 bytes of addresses
+            0x21, 0x21, 0x00, 0x24,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x07,
+            0xdc, 0x04,
+            0x01, 0xbb,
+        ];
+        assert_eq!(header, &expected[..]);
+    }
+
+    #[test]
+    fn v2_header_without_client_is_local() {
+        let header = ProxyProtocol::V2.header(None, v4().1);
+        #[rustfmt::skip]
+        let expected = [
+            0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+            // version 2 LOCAL, unspecified family, no addresses
+            0x20, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(header, &expected[..]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+
+/// Token bucket parameters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Sustained throughput in bytes per second, 0 is taken as 1
+    pub bytes_per_second: u64,
+    /// Maximum number of bytes which may be written at once, 0 is taken as 1
+    pub burst: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64, burst: u64) -> RateLimit {
+        RateLimit {
+            bytes_per_second,
+            burst,
+        }
+    }
+}
+
+/// Relay bandwidth limits, one per direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Throttle {
+    /// Client to destination
+    pub upload: Option<RateLimit>,
+    /// Destination to client
+    pub download: Option<RateLimit>,
+}
+
+impl Throttle {
+    /// Applies the same limit to both directions
+    pub fn symmetric(limit: RateLimit) -> Throttle {
+        Throttle {
+            upload: Some(limit),
+            download: Some(limit),
+        }
+    }
+}
+
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> TokenBucket {
+        let burst = limit.burst.max(1) as f64;
+        TokenBucket {
+            rate: limit.bytes_per_second.max(1) as f64,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn burst(&self) -> usize {
+        self.burst as usize
+    }
+
+    /// Waits until `n` tokens are available and takes them, `n` must not exceed the burst size
+    pub(crate) async fn acquire(&mut self, n: usize) {
+        let n = n as f64;
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last = now;
+            if self.tokens >= n {
+                self.tokens -= n;
+                return;
+            }
+            let wait = (n - self.tokens) / self.rate;
+            Timer::after(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::relay::copy;
+
+    #[test]
+    fn burst_passes_without_waiting() {
+        let mut bucket = TokenBucket::new(RateLimit::new(1000, 500));
+        let start = Instant::now();
+        block_on(bucket.acquire(500));
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(TokenBucket::new(RateLimit::new(0, 0)).burst(), 1);
+    }
+
+    #[test]
+    fn sustained_reads_are_paced() {
+        let mut bucket = TokenBucket::new(RateLimit::new(10_000, 500));
+        let start = Instant::now();
+        block_on(async {
+            // the burst, then 2000 bytes at 10000 per second
+            for _ in 0..5 {
+                bucket.acquire(500).await;
+            }
+        });
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+    }
+
+    #[test]
+    fn throttled_copy_is_slower() {
+        let data = vec![0xaa; 5000];
+        let copy_with = |limit| {
+            let mut out = Vec::new();
+            let start = Instant::now();
+            let n = block_on(copy(&data[..], &mut out, 1024, limit, None, None)).unwrap();
+            assert_eq!((n, out.len()), (5000, 5000));
+            start.elapsed()
+        };
+        let unthrottled = copy_with(None);
+        // 4000 bytes past the burst at 20000 per second
+        let throttled = copy_with(Some(RateLimit::new(20_000, 1000)));
+        assert!(throttled >= Duration::from_millis(190), "{throttled:?}");
+        assert!(throttled > unthrottled);
+    }
+}
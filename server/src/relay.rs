@@ -0,0 +1,271 @@
+use std::{
+    cell::Cell,
+    future::{pending, Future},
+    net::{Shutdown, TcpStream},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use futures_lite::{
+    future::{race, try_zip},
+    io, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+
+use crate::{
+    rate_limit::{RateLimit, Throttle, TokenBucket},
+    shutdown::ShutdownToken,
+};
+
+/// Default size of the relay buffer of each direction
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Relays between two streams in both directions until both reach EOF, returns
+/// the bytes copied from `a` to `b` and from `b` to `a`
+///
+/// The copy loop of the proxies, for proxies with their own handshake: each
+/// direction closes its destination once done, so a stream shutting down its
+/// write side on close, such as a tokio stream through
+/// [`TokioIo`](socks5::compat::TokioIo), sees the half-close while the opposite
+/// direction keeps flowing. `Async<TcpStream>` only flushes on close.
+pub async fn relay<A, B>(a: A, b: B) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    relay_with(a, b, DEFAULT_BUFFER_SIZE, Throttle::default(), None, None).await
+}
+
+/// Same as [`relay`], `throttle.upload` limits `a` to `b` and `throttle.download`
+/// the opposite direction, fails with [`io::ErrorKind::TimedOut`] once nothing
+/// was read for `idle_timeout`
+pub(crate) async fn relay_with<A, B>(
+    a: A,
+    b: B,
+    buffer_size: usize,
+    throttle: Throttle,
+    shutdown: Option<&ShutdownToken>,
+    idle_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (a_r, a_w) = io::split(a);
+    let (b_r, b_w) = io::split(b);
+    let idle = idle_timeout.map(Idle::new);
+    let idle = idle.as_ref();
+    try_zip(
+        copy(a_r, b_w, buffer_size, throttle.upload, shutdown, idle),
+        copy(b_r, a_w, buffer_size, throttle.download, shutdown, idle),
+    )
+    .await
+}
+
+/// TCP stream shutting down its write side on close, which `Async<TcpStream>`
+/// alone only flushes
+pub(crate) struct HalfClose<'a>(pub(crate) &'a Async<TcpStream>);
+
+impl AsyncRead for HalfClose<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HalfClose<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures_lite::ready!(Pin::new(&mut self.0).poll_flush(cx))?;
+        Poll::Ready(self.0.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+/// Time of the last read of a relay, shared by its two directions
+pub(crate) struct Idle {
+    timeout: Duration,
+    last: Cell<Instant>,
+}
+
+impl Idle {
+    pub(crate) fn new(timeout: Duration) -> Idle {
+        Idle {
+            timeout,
+            last: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Resolves once nothing was read for the timeout
+    async fn expired(&self) {
+        loop {
+            let deadline = self.last.get() + self.timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            Timer::at(deadline).await;
+        }
+    }
+}
+
+/// Awaits `read`, 0 as if at EOF once `shutdown` is triggered, and a timeout
+/// error once `idle` expired; a completed read resets `idle`
+pub(crate) async fn guarded_read(
+    read: impl Future<Output = io::Result<usize>>,
+    shutdown: Option<&ShutdownToken>,
+    idle: Option<&Idle>,
+) -> io::Result<usize> {
+    let stop = async {
+        match shutdown {
+            Some(token) => token.wait().await,
+            None => pending().await,
+        }
+        Ok(0)
+    };
+    let expired = async {
+        match idle {
+            Some(idle) => idle.expired().await,
+            None => pending().await,
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "relay idle timeout",
+        ))
+    };
+    let n = race(read, race(stop, expired)).await?;
+    if let Some(idle) = idle {
+        idle.last.set(Instant::now());
+    }
+    Ok(n)
+}
+
+/// Copies `reader` into `writer` until EOF or shutdown, honoring `limit` if any
+///
+/// Reads at most `buffer_size` bytes at a time, or the burst size of `limit` if smaller.
+///
+/// The write side of `writer` is shut down once done, so the peer sees the
+/// half-close while the opposite direction keeps flowing.
+pub(crate) async fn copy<R, W>(
+    mut reader: R,
+    mut writer: W,
+    buffer_size: usize,
+    limit: Option<RateLimit>,
+    shutdown: Option<&ShutdownToken>,
+    idle: Option<&Idle>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut bucket = limit.map(TokenBucket::new);
+    let size = match &bucket {
+        Some(bucket) => bucket.burst().min(buffer_size),
+        None => buffer_size,
+    };
+    let mut buf = vec![0; size.max(1)];
+    let mut total = 0;
+    loop {
+        let n = guarded_read(reader.read(&mut buf), shutdown, idle).await?;
+        if n == 0 {
+            writer.close().await?;
+            return Ok(total);
+        }
+        if let Some(bucket) = &mut bucket {
+            bucket.acquire(n).await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        total += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use futures_lite::future::{block_on, zip};
+
+    use super::*;
+
+    /// Connected pair of loopback TCP streams
+    fn pair() -> (Async<TcpStream>, Async<TcpStream>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Async::new(client).unwrap(), Async::new(server).unwrap())
+    }
+
+    #[test]
+    fn half_close_keeps_the_other_direction_flowing() {
+        let (mut client, proxy_a) = pair();
+        let (proxy_b, mut dest) = pair();
+        let peers = async {
+            client.write_all(b"request").await.unwrap();
+            client.get_ref().shutdown(Shutdown::Write).unwrap();
+            let mut request = Vec::new();
+            dest.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            // the client is done writing, the response must still reach it
+            Timer::after(Duration::from_millis(20)).await;
+            dest.write_all(b"response").await.unwrap();
+            dest.get_ref().shutdown(Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+        };
+        let relay = relay(HalfClose(&proxy_a), HalfClose(&proxy_b));
+        let (relayed, ()) = block_on(zip(relay, peers));
+        assert_eq!(relayed.unwrap(), (7, 8));
+    }
+
+    #[test]
+    fn idle_timeout_ends_a_half_closed_relay() {
+        let (client, proxy_a) = pair();
+        let (proxy_b, _dest) = pair();
+        client.get_ref().shutdown(Shutdown::Write).unwrap();
+        // the destination never answers nor closes
+        let timeout = Some(Duration::from_millis(100));
+        let throttle = Throttle::default();
+        let relay = relay_with(proxy_a, proxy_b, 1024, throttle, None, timeout);
+        let error = block_on(relay).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn reads_reset_the_idle_timeout() {
+        let (mut client, proxy_a) = pair();
+        let (proxy_b, mut dest) = pair();
+        let peers = async {
+            // each chunk within the timeout, the whole exchange not
+            for _ in 0..4 {
+                Timer::after(Duration::from_millis(50)).await;
+                client.write_all(b"ping").await.unwrap();
+            }
+            client.get_ref().shutdown(Shutdown::Write).unwrap();
+            let mut received = Vec::new();
+            dest.read_to_end(&mut received).await.unwrap();
+            dest.get_ref().shutdown(Shutdown::Write).unwrap();
+            received.len()
+        };
+        let timeout = Some(Duration::from_millis(120));
+        let throttle = Throttle::default();
+        let (a, b) = (HalfClose(&proxy_a), HalfClose(&proxy_b));
+        let relay = relay_with(a, b, 1024, throttle, None, timeout);
+        let (relayed, received) = block_on(zip(relay, peers));
+        assert_eq!(relayed.unwrap(), (16, 0));
+        assert_eq!(received, 16);
+    }
+}
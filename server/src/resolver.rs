@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use socks5::address::Resolver;
+
+/// Resolver backed by `async_dns`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = async_dns::lookup(host)
+            .await?
+            .map(|addr| (addr.ip_address, port).into())
+            .collect();
+        if addrs.is_empty() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        Ok(addrs)
+    }
+}
+
+/// Caches the addresses returned by an inner resolver
+///
+/// Entries expire after `ttl`. Once `capacity` names are cached, the least
+/// recently used one is evicted. Clones share the cache.
+#[derive(Clone, Debug)]
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    negative_ttl: Option<Duration>,
+    capacity: usize,
+    cache: Arc<Mutex<Cache>>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// Incremented on each access, orders entries by recency
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// `None` for a name which does not exist
+    addrs: Option<Vec<SocketAddr>>,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration, capacity: usize) -> CachingResolver<R> {
+        CachingResolver {
+            inner,
+            ttl,
+            negative_ttl: None,
+            capacity,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Also caches names which do not exist, for `ttl`
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> CachingResolver<R> {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    fn get(&self, host: &str) -> Option<Option<Vec<SocketAddr>>> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.clock += 1;
+        let clock = cache.clock;
+        let entry = cache.entries.get_mut(host)?;
+        if entry.expires <= Instant::now() {
+            cache.entries.remove(host);
+            return None;
+        }
+        entry.last_used = clock;
+        Some(entry.addrs.clone())
+    }
+
+    fn insert(&self, host: String, addrs: Option<Vec<SocketAddr>>, ttl: Duration) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.entries.len() >= self.capacity && !cache.entries.contains_key(&host) {
+            let lru = cache
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(host, _)| host.clone());
+            if let Some(lru) = lru {
+                cache.entries.remove(&lru);
+            }
+        }
+        cache.clock += 1;
+        let entry = Entry {
+            addrs,
+            expires: Instant::now() + ttl,
+            last_used: cache.clock,
+        };
+        cache.entries.insert(host, entry);
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let key = host.to_ascii_lowercase();
+        match self.get(&key) {
+            Some(Some(mut addrs)) => {
+                addrs.iter_mut().for_each(|addr| addr.set_port(port));
+                return Ok(addrs);
+            }
+            Some(None) => return Err(ErrorKind::NotFound.into()),
+            None => {}
+        }
+        match self.inner.resolve(host, port).await {
+            Ok(addrs) => {
+                self.insert(key, Some(addrs.clone()), self.ttl);
+                Ok(addrs)
+            }
+            Err(e) => {
+                if let (ErrorKind::NotFound, Some(ttl)) = (e.kind(), self.negative_ttl) {
+                    self.insert(key, None, ttl);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Answers names from a static hosts map, asking an inner resolver for the others
+///
+/// Names are matched case-insensitively. Useful for overrides of internal names
+/// and for deterministic tests.
+#[derive(Clone, Debug)]
+pub struct StaticResolver<R> {
+    hosts: HashMap<String, IpAddr>,
+    inner: R,
+}
+
+impl<R> StaticResolver<R> {
+    pub fn new(hosts: HashMap<String, IpAddr>, inner: R) -> StaticResolver<R> {
+        let hosts = hosts
+            .into_iter()
+            .map(|(host, ip)| (host.to_ascii_lowercase(), ip))
+            .collect();
+        StaticResolver { hosts, inner }
+    }
+}
+
+impl<R: Resolver> Resolver for StaticResolver<R> {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(ip) => Ok(vec![SocketAddr::new(*ip, port)]),
+            None => self.inner.resolve(host, port).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_io::Timer;
+    use futures_lite::future::block_on;
+
+    use super::*;
+
+    /// Resolves any name to 192.0.2.1 but `missing.example`, counting the lookups
+    #[derive(Clone, Default)]
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Counting {
+        fn lookups(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Resolver for Counting {
+        async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            match host {
+                "missing.example" => Err(ErrorKind::NotFound.into()),
+                _ => Ok(vec![SocketAddr::from(([192, 0, 2, 1], port))]),
+            }
+        }
+    }
+
+    #[test]
+    fn cached_addresses_expire_after_the_ttl() {
+        let inner = Counting::default();
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_millis(100), 8);
+        block_on(async {
+            resolver.resolve("www.example", 80).await.unwrap();
+            resolver.resolve("WWW.example", 80).await.unwrap();
+            assert_eq!(inner.lookups(), 1);
+            Timer::after(Duration::from_millis(150)).await;
+            resolver.resolve("www.example", 80).await.unwrap();
+            assert_eq!(inner.lookups(), 2);
+        });
+    }
+
+    #[test]
+    fn least_recently_used_name_is_evicted() {
+        let inner = Counting::default();
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 2);
+        block_on(async {
+            resolver.resolve("a.example", 80).await.unwrap();
+            resolver.resolve("b.example", 80).await.unwrap();
+            // a is now more recently used than b
+            resolver.resolve("a.example", 80).await.unwrap();
+            assert_eq!(inner.lookups(), 2);
+            resolver.resolve("c.example", 80).await.unwrap();
+            assert_eq!(inner.lookups(), 3);
+            resolver.resolve("a.example", 80).await.unwrap();
+            assert_eq!(inner.lookups(), 3);
+            resolver.resolve("b.example", 80).await.unwrap();
+            assert_eq!(inner.lookups(), 4);
+        });
+    }
+
+    #[test]
+    fn missing_names_are_cached_only_with_a_negative_ttl() {
+        block_on(async {
+            let inner = Counting::default();
+            let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 8);
+            for _ in 0..2 {
+                let error = resolver.resolve("missing.example", 80).await.unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::NotFound);
+            }
+            assert_eq!(inner.lookups(), 2);
+
+            let inner = Counting::default();
+            let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 8)
+                .with_negative_ttl(Duration::from_millis(100));
+            for _ in 0..2 {
+                let error = resolver.resolve("missing.example", 80).await.unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::NotFound);
+            }
+            assert_eq!(inner.lookups(), 1);
+            Timer::after(Duration::from_millis(150)).await;
+            resolver.resolve("missing.example", 80).await.unwrap_err();
+            assert_eq!(inner.lookups(), 2);
+        });
+    }
+
+    #[test]
+    fn cache_hit_takes_the_requested_port() {
+        let inner = Counting::default();
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 8);
+        block_on(async {
+            let addrs = resolver.resolve("www.example", 80).await.unwrap();
+            assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 80))]);
+            let addrs = resolver.resolve("www.example", 443).await.unwrap();
+            assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 443))]);
+            assert_eq!(inner.lookups(), 1);
+        });
+    }
+
+    #[test]
+    fn static_names_match_any_case_and_fall_through() {
+        let inner = Counting::default();
+        let hosts = HashMap::from([("Internal.Example".to_owned(), IpAddr::from([10, 0, 0, 7]))]);
+        let resolver = StaticResolver::new(hosts, inner.clone());
+        block_on(async {
+            let addrs = resolver.resolve("internal.EXAMPLE", 8080).await.unwrap();
+            assert_eq!(addrs, [SocketAddr::from(([10, 0, 0, 7], 8080))]);
+            assert_eq!(inner.lookups(), 0);
+            let addrs = resolver.resolve("www.example", 80).await.unwrap();
+            assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 80))]);
+            assert_eq!(inner.lookups(), 1);
+        });
+    }
+}
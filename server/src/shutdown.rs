@@ -0,0 +1,64 @@
+use std::{
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use event_listener::Event;
+
+/// Cloneable cancellation handle shared by running proxies
+///
+/// Once triggered, relays stop reading new data and are given
+/// [`ServerConfig::shutdown_grace`](crate::ServerConfig::shutdown_grace) to
+/// flush what they have already read.
+#[derive(Clone, Default)]
+pub struct ShutdownToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    triggered: AtomicBool,
+    event: Event,
+}
+
+impl ShutdownToken {
+    pub fn new() -> ShutdownToken {
+        ShutdownToken::default()
+    }
+
+    /// Triggers the shutdown, waking every waiting proxy
+    pub fn shutdown(&self) {
+        if !self.inner.triggered.swap(true, Ordering::SeqCst) {
+            self.inner.event.notify(usize::MAX);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the shutdown has been triggered
+    pub async fn wait(&self) {
+        loop {
+            if self.is_shutdown() {
+                return;
+            }
+            let listener = self.inner.event.listen();
+            if self.is_shutdown() {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+impl Debug for ShutdownToken {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("ShutdownToken")
+            .field("triggered", &self.is_shutdown())
+            .finish()
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use async_io::Async;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+/// Options applied to outbound connections
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    /// Disables Nagle's algorithm, lowers latency for interactive traffic
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent, keepalive is off if `None`
+    pub keepalive: Option<Duration>,
+    /// Local address to bind before connecting, selects the source IP (and with it
+    /// the egress interface) on multi-homed hosts
+    pub bind: Option<SocketAddr>,
+    /// Order in which the addresses of a domain name are tried
+    pub family: FamilyPreference,
+}
+
+/// Address family tried first when a domain name resolves to both IPv4 and IPv6
+/// addresses, such as IPv4 on hosts with broken IPv6 connectivity
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FamilyPreference {
+    /// The order returned by the resolver
+    #[default]
+    SystemDefault,
+    PreferV4,
+    PreferV6,
+}
+
+impl FamilyPreference {
+    /// Moves the preferred family first, keeping the order within each family
+    pub fn sort(self, addrs: &mut [SocketAddr]) {
+        match self {
+            FamilyPreference::SystemDefault => {}
+            FamilyPreference::PreferV4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            FamilyPreference::PreferV6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        }
+    }
+}
+
+impl SocketOptions {
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the first of `addrs` accepting the connection, in the order of
+/// `options.family`, returns the stream with the address it is connected to
+///
+/// `addrs` must not be empty, the error of the last attempt is returned.
+pub(crate) async fn connect_any(
+    addrs: Vec<SocketAddr>,
+    options: &SocketOptions,
+) -> io::Result<(Async<TcpStream>, SocketAddr)> {
+    connect_any_with(addrs, options.family, |addr| connect(addr, options)).await
+}
+
+/// Same as [`connect_any`], connecting to each address with `dial`
+async fn connect_any_with<T, F, D>(
+    mut addrs: Vec<SocketAddr>,
+    family: FamilyPreference,
+    dial: D,
+) -> io::Result<(T, SocketAddr)>
+where
+    D: Fn(SocketAddr) -> F,
+    F: Future<Output = io::Result<T>>,
+{
+    family.sort(&mut addrs);
+    let mut last_error = None;
+    for addr in addrs {
+        match dial(addr).await {
+            Ok(stream) => return Ok((stream, addr)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| ErrorKind::AddrNotAvailable.into()))
+}
+
+/// Connects to `addr` and applies `options` to the new socket
+pub(crate) async fn connect(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<Async<TcpStream>> {
+    let stream = match options.bind {
+        Some(local) => connect_from(local, addr).await?,
+        None => Async::<TcpStream>::connect(addr).await?,
+    };
+    options.apply(stream.get_ref())?;
+    Ok(stream)
+}
+
+async fn connect_from(local: SocketAddr, addr: SocketAddr) -> io::Result<Async<TcpStream>> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&local.into())?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        Err(e) if in_progress(&e) => {}
+        Err(e) => return Err(e),
+    }
+    let stream = Async::new_nonblocking(TcpStream::from(socket))?;
+
+    // the stream becomes writable when connected
+    stream.writable().await?;
+    match stream.get_ref().take_error()? {
+        None => Ok(stream),
+        Some(e) => Err(e),
+    }
+}
+
+fn in_progress(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true;
+    }
+    e.kind() == ErrorKind::WouldBlock
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        net::{Ipv6Addr, SocketAddrV6},
+    };
+
+    use futures_lite::future::block_on;
+    use socks5::address::Address;
+
+    use super::*;
+    use crate::DnsResolver;
+
+    #[test]
+    fn dials_scoped_address_with_its_scope() {
+        // fe80::1%3
+        let scoped = SocketAddrV6::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1), 80, 0, 3);
+        let dialed = RefCell::new(Vec::new());
+        let dial = |addr| {
+            dialed.borrow_mut().push(addr);
+            async { Ok(()) }
+        };
+        let (_, connected) = block_on(async {
+            let addrs = Address::from(scoped).to_socket_addrs(&DnsResolver).await;
+            connect_any_with(addrs.unwrap(), FamilyPreference::default(), dial).await
+        })
+        .unwrap();
+        assert_eq!(connected, SocketAddr::V6(scoped));
+        assert_eq!(dialed.into_inner(), [SocketAddr::V6(scoped)]);
+    }
+
+    #[test]
+    fn tries_the_next_address_after_a_failure() {
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 80));
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 80));
+        let dial = |addr: SocketAddr| async move {
+            match addr.is_ipv6() {
+                true => Err(io::Error::from(ErrorKind::ConnectionRefused)),
+                false => Ok(()),
+            }
+        };
+        let connected = block_on(connect_any_with(
+            vec![v4, v6],
+            FamilyPreference::PreferV6,
+            dial,
+        ));
+        assert_eq!(connected.unwrap().1, v4);
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::Arc,
+};
+
+use socks5::head::PasswordRequest;
+
+/// Accounts allowed to authenticate with the username/password method (RFC 1929)
+///
+/// Once set in [`ServerConfig::users`](crate::ServerConfig::users), clients must
+/// offer the `PASSWORD` method. Clones share the accounts.
+#[derive(Clone, Default)]
+pub struct Users {
+    accounts: Arc<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Users {
+    pub fn new() -> Users {
+        Users::default()
+    }
+
+    /// Adds an account, replacing the password of an existing one
+    pub fn with_user(mut self, username: &[u8], password: &[u8]) -> Users {
+        Arc::make_mut(&mut self.accounts).insert(username.to_vec(), password.to_vec());
+        self
+    }
+
+    /// Whether the request carries the password of an existing account
+    pub fn check(&self, request: &PasswordRequest) -> bool {
+        match self.accounts.get(request.username()) {
+            Some(password) => constant_time_eq(password, request.password()),
+            None => false,
+        }
+    }
+}
+
+/// Compares without returning early, so that timing does not tell how much of a
+/// guessed password is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Passwords are never printed
+impl Debug for Users {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let usernames: Vec<_> = self
+            .accounts
+            .keys()
+            .map(|username| String::from_utf8_lossy(username))
+            .collect();
+        f.debug_struct("Users")
+            .field("usernames", &usernames)
+            .finish()
+    }
+}
@@ -0,0 +1,128 @@
+//! Encoding and decoding of handshake messages
+//!
+//! Run with `cargo bench -p socks5`. The `read` group decodes a million request
+//! headers from an in-memory cursor through the async [`Decode::read`].
+
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_lite::{future::block_on, io::sink};
+use socks5::{
+    address::Address,
+    head::{AuthenticationRequest, TcpRequestHeader},
+    message::{Command, Method},
+    ser::{Decode, DecodeSlice, Encode},
+};
+
+fn addresses() -> [(&'static str, Address); 3] {
+    let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    [
+        ("ipv4", v4.into()),
+        ("ipv6", v6.into()),
+        (
+            "domain",
+            Address::from_domain("www.example.com", 443).unwrap(),
+        ),
+    ]
+}
+
+fn address(c: &mut Criterion) {
+    let mut group = c.benchmark_group("address");
+    for (name, address) in addresses() {
+        group.bench_with_input(BenchmarkId::new("encode", name), &address, |b, address| {
+            b.iter(|| black_box(address).encode())
+        });
+        let encoded = address.encode();
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| Address::decode_slice(black_box(encoded)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn request_header(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_header");
+    for (name, address) in addresses() {
+        let header = TcpRequestHeader::new(Command::Connect, address);
+        group.bench_with_input(
+            BenchmarkId::new("round_trip", name),
+            &header,
+            |b, header| {
+                b.iter(|| {
+                    let encoded = black_box(header).encode();
+                    TcpRequestHeader::decode_slice(&encoded).unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Headers read back to back by the `read` benchmark
+const READ_COUNT: usize = 1_000_000;
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(READ_COUNT as u64));
+    for (name, address) in addresses() {
+        let header = TcpRequestHeader::new(Command::Connect, address);
+        let encoded = header.as_bytes().repeat(READ_COUNT);
+        group.bench_with_input(BenchmarkId::new("read", name), &encoded, |b, encoded| {
+            b.iter(|| {
+                block_on(async {
+                    let mut cursor = black_box(&encoded[..]);
+                    for _ in 0..READ_COUNT {
+                        TcpRequestHeader::read(&mut cursor).await.unwrap();
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn authentication_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("authentication_request");
+    for count in [1, 16, 255] {
+        // built from the wire, as the constructors drop duplicate methods
+        let mut encoded = vec![count as u8];
+        let methods = [Method::NONE, Method::GSSAPI, Method::PASSWORD];
+        encoded.extend(methods.iter().cycle().take(count).map(|m| m.as_u8()));
+        let (request, _) = AuthenticationRequest::decode_slice(&encoded).unwrap();
+        assert_eq!(request.methods().len(), count);
+        group.bench_with_input(BenchmarkId::new("encode", count), &request, |b, request| {
+            b.iter(|| black_box(request).encode())
+        });
+        group.bench_with_input(BenchmarkId::new("decode", count), &encoded, |b, encoded| {
+            b.iter(|| AuthenticationRequest::decode_slice(black_box(encoded)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    let (_, address) = addresses().into_iter().last().unwrap();
+    let header = TcpRequestHeader::new(Command::Connect, address);
+    group.bench_function("write_to", |b| {
+        b.iter(|| block_on(black_box(&header).write_to(&mut sink())).unwrap())
+    });
+    let mut buf = BytesMut::new();
+    group.bench_function("write_to_reusing", |b| {
+        b.iter(|| block_on(black_box(&header).write_to_reusing(&mut sink(), &mut buf)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    address,
+    request_header,
+    read,
+    authentication_request,
+    write
+);
+criterion_main!(benches);
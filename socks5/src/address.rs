@@ -1,103 +1,396 @@
-use std::{
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use core::future::Future;
+use core::{
     convert::{TryFrom, TryInto},
-    fmt::{Debug, Display},
-    future::Future,
+    fmt::{Debug, Display, Formatter},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::Utf8Error,
 };
+#[cfg(feature = "std")]
+use std::io;
 
-use bytes::{BufMut, Bytes, BytesMut};
-use futures_lite::AsyncReadExt;
+use bytes::{BufMut, BytesMut};
 use tinyvec::ArrayVec;
 
 use crate::{
-    error::Error,
+    error::{Error, ErrorKind},
     message::Replies,
-    ser::{Decode, Encode},
+    ser::{DecodeLimits, DecodeSlice, Encode, SliceReader},
 };
 
+/// Domain name resolver
+#[cfg(feature = "std")]
+pub trait Resolver {
+    /// Resolves `host` into socket addresses with `port`, in preference order
+    ///
+    /// A name which does not exist is reported as [`io::ErrorKind::NotFound`], any
+    /// other error is taken as a temporary failure.
+    fn resolve(&self, host: &str, port: u16) -> impl Future<Output = io::Result<Vec<SocketAddr>>>;
+}
+
+/// Asks `first`, falling through to `second` on temporary failures
+///
+/// A [`io::ErrorKind::NotFound`] error of `first` is returned as is, without
+/// asking `second`. Resolvers of different types can be combined, and more than
+/// two by nesting: `FallbackResolver::new(a, FallbackResolver::new(b, c))`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct FallbackResolver<A, B> {
+    first: A,
+    second: B,
+}
+
+#[cfg(feature = "std")]
+impl<A, B> FallbackResolver<A, B> {
+    pub fn new(first: A, second: B) -> FallbackResolver<A, B> {
+        FallbackResolver { first, second }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: Resolver, B: Resolver> Resolver for FallbackResolver<A, B> {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match self.first.resolve(host, port).await {
+            Ok(addrs) => Ok(addrs),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(e),
+            Err(_) => self.second.resolve(host, port).await,
+        }
+    }
+}
+
+/// Resolver calling the function given to the deprecated [`Address::lookup`]
+#[cfg(feature = "std")]
+struct LookupFn<'a, F> {
+    name: &'a [u8],
+    f: F,
+}
+
+#[cfg(feature = "std")]
+impl<'a, F, T, E> Resolver for LookupFn<'a, F>
+where
+    F: Fn(&'a [u8], u16) -> T,
+    T: Future<Output = Result<SocketAddr, E>>,
+    E: Display,
+{
+    async fn resolve(&self, _host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match (self.f)(self.name, port).await {
+            Ok(addr) => Ok(alloc::vec![addr]),
+            // any failure of the function is taken as an unreachable host
+            Err(e) => Err(io::Error::new(io::ErrorKind::NotFound, format!("{e}"))),
+        }
+    }
+}
+
 /// SOCKS5 address type
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Address {
     /// Socket address
+    ///
+    /// The IPv6 scope id and flow info are kept in memory, so a link-local
+    /// destination such as `fe80::1%2` can be dialed, but they have no place in
+    /// the wire format: they are dropped by encoding and decoded as `0`.
     Socket(SocketAddr),
     /// Domain name address
-    DomainName(Box<ArrayVec<[u8; 2048]>>, u16),
+    DomainName(Box<ArrayVec<[u8; 255]>>, u16),
 }
 
 impl Address {
+    /// IPv4 unspecified address `0.0.0.0:port`, as sent by UDP ASSOCIATE and BIND
+    /// requests which do not know their address yet
+    pub fn unspecified_v4(port: u16) -> Address {
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into()
+    }
+
+    /// IPv6 unspecified address `[::]:port`
+    pub fn unspecified_v6(port: u16) -> Address {
+        SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into()
+    }
+
+    /// Whether this is an unspecified IP address, the port is not considered
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            Address::Socket(addr) => addr.ip().is_unspecified(),
+            Address::DomainName(..) => false,
+        }
+    }
+
+    /// Domain name address, rejects empty names, names longer than 255 bytes and
+    /// names containing control characters
+    pub fn from_domain(name: &str, port: u16) -> Result<Address, Error> {
+        check_domain(name.as_bytes(), DomainPolicy::default())?;
+        Address::try_from((name.as_bytes(), port))
+    }
+
+    /// Socket address parsed from `s`, such as `127.0.0.1:1080` or `[::1]:1080`,
+    /// anything else including domain names is an error
+    pub fn socket_from_str(s: &str) -> Result<Address, Error> {
+        let addr: SocketAddr = s.parse()?;
+        Ok(Address::Socket(addr))
+    }
+
+    /// Socket address of the first address `addr` resolves to, with the blocking
+    /// resolver of the standard library, such as `"example.com:80"`
+    ///
+    /// Blocks the thread while resolving, meant for synchronous setup code such
+    /// as parsing a configuration; use [`Address::to_socket_addrs`] elsewhere.
+    #[cfg(feature = "std")]
+    pub fn resolve_std<A: std::net::ToSocketAddrs>(addr: A) -> io::Result<Address> {
+        match addr.to_socket_addrs()?.next() {
+            Some(addr) => Ok(Address::Socket(addr)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no address to resolve to",
+            )),
+        }
+    }
+
+    /// Address of a host given by name or IP address
+    ///
+    /// Unicode domain names are converted to their ASCII form with IDNA, so that
+    /// `münchen.de` is sent as `xn--mnchen-3ya.de`; ASCII names are kept as is.
+    #[cfg(feature = "idna")]
+    pub fn from_host(host: &str, port: u16) -> Result<Address, Error> {
+        if let Ok(ip) = host.parse::<core::net::IpAddr>() {
+            return Ok(SocketAddr::new(ip, port).into());
+        }
+        if host.is_ascii() {
+            return Address::from_domain(host, port);
+        }
+        let name = idna::domain_to_ascii(host).map_err(|e| {
+            Error::invalid_address(format!("invalid international domain name: {e}"))
+        })?;
+        Address::from_domain(&name, port)
+    }
+
+    /// Address type of this address on the wire
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            Address::Socket(SocketAddr::V4(_)) => AddressType::Ipv4,
+            Address::Socket(SocketAddr::V6(_)) => AddressType::Ipv6,
+            Address::DomainName(..) => AddressType::DomainName,
+        }
+    }
+
+    /// Domain name as a string, `None` for socket addresses
+    ///
+    /// The domain is received as raw bytes, so it may not be valid UTF-8; callers
+    /// decide whether to reject it or convert it lossily.
+    pub fn domain_str(&self) -> Option<Result<&str, Utf8Error>> {
+        match self {
+            Address::Socket(_) => None,
+            Address::DomainName(name, _) => Some(core::str::from_utf8(name)),
+        }
+    }
+
+    /// Resolves the address, socket addresses are returned unchanged
+    ///
+    /// A domain name resolving to multiple addresses yields the first one returned
+    /// by `resolver`, so resolvers should return addresses in preference order.
+    #[cfg(feature = "std")]
+    pub async fn to_socket_addr<R: Resolver>(&self, resolver: &R) -> Result<SocketAddr, Error> {
+        let addrs = self.to_socket_addrs(resolver).await?;
+        Ok(addrs[0])
+    }
+
+    /// Resolves the address with `f`, socket addresses are returned unchanged
+    ///
+    /// A failure of `f` is answered with [`Replies::HostUnreachable`].
+    #[cfg(feature = "std")]
+    #[deprecated(note = "implement `Resolver` and use `Address::to_socket_addr`")]
     pub async fn lookup<'a, F, T, E>(&'a self, f: F) -> Result<SocketAddr, Error>
     where
         F: Fn(&'a [u8], u16) -> T,
         T: Future<Output = Result<SocketAddr, E>>,
         E: Display,
     {
-        let addr = match self {
-            Address::Socket(addr) => *addr,
-            Address::DomainName(name, port) => f(name, *port).await.map_err(|e| {
-                Error::new(
-                    Replies::HostUnreachable,
-                    format!("domain \"{name}\" resolving failed: {e}"),
-                )
-            })?,
+        let name = match self {
+            Address::Socket(addr) => return Ok(*addr),
+            Address::DomainName(name, _) => name.as_slice(),
         };
-        Ok(addr)
+        self.to_socket_addr(&LookupFn { name, f }).await
+    }
+
+    /// Resolves the address into all its socket addresses, in the order returned
+    /// by `resolver`, for callers racing connections or balancing load across them
+    ///
+    /// A socket address yields itself. The result is never empty, a name without
+    /// any address is an error.
+    ///
+    /// The reply of the error tells a name which does not exist, answered with
+    /// [`Replies::HostUnreachable`], from a failing resolver, answered with
+    /// [`Replies::GeneralFailure`] whatever its [`io::Error`].
+    #[cfg(feature = "std")]
+    pub async fn to_socket_addrs<R: Resolver>(
+        &self,
+        resolver: &R,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let (name, port) = match self {
+            Address::Socket(addr) => return Ok(alloc::vec![*addr]),
+            Address::DomainName(name, port) => (name, *port),
+        };
+        let name = core::str::from_utf8(name).map_err(|e| {
+            Error::invalid_address(format!("domain name is not valid UTF-8: {e}"))
+                .with_source(e)
+                .with_address(self.clone())
+        })?;
+        let resolving_failed = |reply, e: &dyn Display| {
+            Error::new(reply, format!("resolving failed: {e}")).with_address(self.clone())
+        };
+        let addrs = resolver.resolve(name, port).await.map_err(|e| {
+            let reply = match e.kind() {
+                io::ErrorKind::NotFound => Replies::HostUnreachable,
+                _ => Replies::GeneralFailure,
+            };
+            resolving_failed(reply, &e).with_source(e)
+        })?;
+        if addrs.is_empty() {
+            return Err(resolving_failed(
+                Replies::HostUnreachable,
+                &"no address found",
+            ));
+        }
+        Ok(addrs)
+    }
+
+    /// Displays the address with identifying parts masked, suitable for logs
+    ///
+    /// Masking rules:
+    ///
+    /// * IPv4: the last octet is replaced by `xxx`, `203.0.113.7:443` becomes `203.0.113.xxx:443`
+    /// * IPv6: the last four segments (the interface identifier) are replaced by `xxxx`,
+    ///   `[2001:db8:1:2:3:4:5:6]:443` becomes `[2001:db8:1:2:xxxx:xxxx:xxxx:xxxx]:443`
+    /// * Domain name: the top-level label is kept, of the remaining part only the first and
+    ///   last two characters are kept and the middle is elided, `example.com:443` becomes
+    ///   `ex…le.com:443`; a remaining part of four characters or fewer is elided entirely,
+    ///   `abc.com:443` becomes `….com:443`
+    ///
+    /// The port is never masked.
+    pub fn redacted(&self) -> impl Display + '_ {
+        Redacted(self)
     }
 }
 
-impl<T: AsyncReadExt + Unpin> Decode<T> for Address {
-    async fn decode(r: &mut T) -> crate::error::Result<Self> {
-        let addr_type = Self::read_u8(r).await?;
-        let addr_type = AddressType::try_from(addr_type)?;
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            Address::Socket(addr) => write!(f, "{addr}"),
+            Address::DomainName(name, port) => {
+                write!(f, "{}:{port}", String::from_utf8_lossy(name))
+            }
+        }
+    }
+}
+
+struct Redacted<'a>(&'a Address);
+
+impl Display for Redacted<'_> {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self.0 {
+            Address::Socket(SocketAddr::V4(addr)) => {
+                let [a, b, c, _] = addr.ip().octets();
+                write!(f, "{a}.{b}.{c}.xxx:{}", addr.port())
+            }
+            Address::Socket(SocketAddr::V6(addr)) => {
+                let [a, b, c, d, ..] = addr.ip().segments();
+                write!(
+                    f,
+                    "[{a:x}:{b:x}:{c:x}:{d:x}:xxxx:xxxx:xxxx:xxxx]:{}",
+                    addr.port()
+                )
+            }
+            Address::DomainName(name, port) => {
+                let name = String::from_utf8_lossy(name);
+                let (head, tld) = match name.rfind('.') {
+                    Some(i) => name.split_at(i),
+                    None => (name.as_ref(), ""),
+                };
+                let chars: Vec<char> = head.chars().collect();
+                if chars.len() > 4 {
+                    let first: String = chars[..2].iter().collect();
+                    let last: String = chars[chars.len() - 2..].iter().collect();
+                    write!(f, "{first}…{last}{tld}:{port}")
+                } else {
+                    write!(f, "…{tld}:{port}")
+                }
+            }
+        }
+    }
+}
+
+impl DecodeSlice for Address {
+    fn decode_slice(buf: &[u8]) -> crate::error::Result<(Self, usize)> {
+        Address::decode_slice_limited(buf, &DecodeLimits::default())
+    }
 
-        match addr_type {
+    fn decode_slice_limited(
+        buf: &[u8],
+        limits: &DecodeLimits,
+    ) -> crate::error::Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let addr_type = AddressType::try_from(r.u8()?)?;
+
+        let addr = match addr_type {
             AddressType::Ipv4 => {
-                let mut buf = vec![0; 6];
-                r.read_exact(&mut buf).await?;
+                let buf = r.bytes(6)?;
                 let v4addr: [u8; 4] = buf[0..4].try_into()?;
                 let v4addr: Ipv4Addr = v4addr.into();
                 let port: [u8; 2] = buf[4..6].try_into()?;
                 let port = u16::from_be_bytes(port);
-                Ok(Address::Socket(SocketAddr::V4(SocketAddrV4::new(
-                    v4addr, port,
-                ))))
+                Address::Socket(SocketAddr::V4(SocketAddrV4::new(v4addr, port)))
             }
             AddressType::Ipv6 => {
-                let mut buf = vec![0; 18];
-                r.read_exact(&mut buf).await?;
+                let buf = r.bytes(18)?;
                 let v6addr: [u8; 16] = buf[0..16].try_into()?;
                 let v6addr: Ipv6Addr = v6addr.into();
                 let port: [u8; 2] = buf[16..18].try_into()?;
                 let port = u16::from_be_bytes(port);
-                Ok(Address::Socket(SocketAddr::V6(SocketAddrV6::new(
-                    v6addr, port, 0, 0,
-                ))))
+                Address::Socket(SocketAddr::V6(SocketAddrV6::new(v6addr, port, 0, 0)))
             }
             AddressType::DomainName => {
-                let domain_len = Self::read_u8(r).await? as usize;
-                let mut buf = vec![0; domain_len + 2];
-                r.read_exact(&mut buf).await?;
+                let domain_len = r.u8()? as usize;
+                let buf = r.bytes(domain_len + 2)?;
+                check_domain(&buf[..domain_len], limits.domain_policy)?;
                 let mut domain = ArrayVec::new();
                 domain.extend_from_slice(&buf[..domain_len]);
                 let port: [u8; 2] = buf[domain_len..domain_len + 2].try_into()?;
                 let port = u16::from_be_bytes(port);
-                Ok(Address::DomainName(Box::new(domain), port))
+                Address::DomainName(Box::new(domain), port)
             }
+        };
+        addr.check_limits(limits)?;
+        Ok((addr, r.consumed()))
+    }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> crate::error::Result<()> {
+        match self {
+            Address::DomainName(name, _) => {
+                DecodeLimits::check("domain length", name.len(), limits.max_domain_len)
+            }
+            Address::Socket(_) => Ok(()),
         }
     }
 }
 
 impl Encode for Address {
-    fn encode(&self) -> Bytes {
-        let mut buffer = BytesMut::new();
+    fn encoded_len(&self) -> usize {
+        let addr_len = match self {
+            Address::Socket(SocketAddr::V4(_)) => 4,
+            Address::Socket(SocketAddr::V6(_)) => 16,
+            Address::DomainName(name, _) => 1 + name.len(),
+        };
+        1 + addr_len + 2
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.address_type().as_u8());
         match self {
             Address::Socket(addr) => match addr {
                 SocketAddr::V4(addr) => {
-                    buffer.put_u8(AddressType::Ipv4 as u8);
                     buffer.put_slice(&addr.ip().octets());
                     buffer.put_u16(addr.port());
                 }
                 SocketAddr::V6(addr) => {
-                    buffer.put_u8(AddressType::Ipv6 as u8);
                     for seg in &addr.ip().segments() {
                         buffer.put_u16(*seg);
                     }
@@ -105,13 +398,11 @@ impl Encode for Address {
                 }
             },
             Address::DomainName(dnaddr, port) => {
-                buffer.put_u8(AddressType::DomainName as u8);
                 buffer.put_u8(dnaddr.len() as u8);
                 buffer.put_slice(dnaddr);
                 buffer.put_u16(*port);
             }
         }
-        buffer.freeze()
     }
 }
 
@@ -135,20 +426,89 @@ impl From<SocketAddrV6> for Address {
     }
 }
 
-impl<'a, T: Into<&'a [u8]>> From<(T, u16)> for Address {
-    fn from((host, port): (T, u16)) -> Address {
-        let s = host.into();
+/// Domain name address, rejects names longer than 255 bytes but does not
+/// validate the name otherwise, see [`Address::from_domain`]
+impl<'a, T: Into<&'a [u8]>> TryFrom<(T, u16)> for Address {
+    type Error = Error;
+
+    fn try_from((host, port): (T, u16)) -> Result<Address, Error> {
+        let name = host.into();
+        check_domain_len(name)?;
         let mut domain = ArrayVec::new();
-        domain.extend_from_slice(s);
-        Address::DomainName(Box::new(domain), port)
+        domain.extend_from_slice(name);
+        Ok(Address::DomainName(Box::new(domain), port))
+    }
+}
+
+/// Characters accepted in domain names, by decoding with
+/// [`DecodeLimits::domain_policy`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DomainPolicy {
+    /// Rejects NUL and other control characters, which are never part of a
+    /// legitimate host name
+    #[default]
+    Standard,
+    /// Accepts any byte
+    Permissive,
+    /// Only accepts host name characters: ASCII letters, digits, hyphens and dots
+    Strict,
+}
+
+/// Rejects empty domain names, and those `policy` does not accept
+fn check_domain(name: &[u8], policy: DomainPolicy) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(Error::invalid_address("domain name is empty"));
+    }
+    check_domain_len(name)?;
+    if policy == DomainPolicy::Strict {
+        let is_host_char = |c: &u8| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'.';
+        return match name.iter().find(|c| !is_host_char(c)) {
+            Some(c) => Err(Error::invalid_address(format!(
+                "domain name contains invalid character {c:#x}"
+            ))),
+            None => Ok(()),
+        };
+    }
+    if policy == DomainPolicy::Permissive {
+        return Ok(());
+    }
+    match name.iter().find(|c| c.is_ascii_control()) {
+        Some(c) => Err(Error::invalid_address(format!(
+            "domain name contains control character {c:#x}"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The length of a domain name is encoded on one byte
+fn check_domain_len(name: &[u8]) -> Result<(), Error> {
+    if name.len() > u8::MAX as usize {
+        return Err(Error::with_kind(
+            ErrorKind::AddressTooLong,
+            Replies::AddressTypeNotSupported,
+            format!("domain name of {} bytes exceeds 255 bytes", name.len()),
+        ));
     }
+    Ok(())
 }
 
-#[derive(Copy, Clone)]
-enum AddressType {
-    Ipv4 = 1,
-    DomainName = 3,
-    Ipv6 = 4,
+/// SOCKS5 address type (ATYP)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AddressType {
+    /// IPv4 address, 4 octets
+    Ipv4 = 0x01,
+    /// Fully-qualified domain name, prefixed with one octet of length
+    DomainName = 0x03,
+    /// IPv6 address, 16 octets
+    Ipv6 = 0x04,
+}
+
+impl AddressType {
+    /// Raw ATYP value on the wire
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
 }
 
 impl TryFrom<u8> for AddressType {
@@ -160,7 +520,8 @@ impl TryFrom<u8> for AddressType {
             3 => AddressType::DomainName,
             4 => AddressType::Ipv6,
             c => {
-                return Err(Error::new(
+                return Err(Error::with_kind(
+                    ErrorKind::UnsupportedAddressType(c),
                     Replies::AddressTypeNotSupported,
                     format!("unsupported address type {:#x}", c),
                 ))
@@ -169,3 +530,242 @@ impl TryFrom<u8> for AddressType {
         Ok(m)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_longer_than_255_bytes_is_rejected() {
+        let name = [b'a'; 300];
+        let err = Address::try_from((&name[..], 80)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddressTooLong);
+        assert_eq!(err.reply, Replies::AddressTypeNotSupported);
+        let name = core::str::from_utf8(&name).unwrap();
+        assert!(Address::from_domain(name, 80).is_err());
+    }
+
+    #[test]
+    fn domain_of_255_bytes_encodes_its_length() {
+        let name = [b'a'; 255];
+        let addr = Address::try_from((&name[..], 80)).unwrap();
+        let bytes = addr.encode();
+        assert_eq!(bytes.len(), 1 + 1 + 255 + 2);
+        assert_eq!(&bytes[..2], &[0x03, 0xff]);
+        assert_eq!(Address::decode_slice(&bytes).unwrap(), (addr, bytes.len()));
+    }
+
+    fn encoded_domain(name: &[u8]) -> Vec<u8> {
+        let mut buf = alloc::vec![0x03, name.len() as u8];
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(&80u16.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn control_characters_are_rejected_by_default() {
+        for name in [&b"exa\0mple.com"[..], b"example.com\n", b"a\r\nb"] {
+            let buf = encoded_domain(name);
+            let err = Address::decode_slice(&buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidAddress);
+            let limits = DecodeLimits::default();
+            assert!(Address::decode_slice_limited(&buf, &limits).is_err());
+        }
+        assert!(Address::from_domain("exa\0mple.com", 80).is_err());
+        assert!(Address::from_domain("example.com\n", 80).is_err());
+    }
+
+    #[test]
+    fn permissive_policy_accepts_control_characters() {
+        let limits = DecodeLimits {
+            domain_policy: DomainPolicy::Permissive,
+            ..DecodeLimits::default()
+        };
+        for name in [&b"exa\0mple.com"[..], b"example.com\n"] {
+            let buf = encoded_domain(name);
+            let (addr, n) = Address::decode_slice_limited(&buf, &limits).unwrap();
+            assert_eq!(n, buf.len());
+            assert_eq!(addr, Address::try_from((name, 80)).unwrap());
+        }
+    }
+
+    #[test]
+    fn strict_policy_only_accepts_host_name_characters() {
+        let limits = DecodeLimits {
+            domain_policy: DomainPolicy::Strict,
+            ..DecodeLimits::default()
+        };
+        let buf = encoded_domain(b"www-1.example.com");
+        assert!(Address::decode_slice_limited(&buf, &limits).is_ok());
+        for name in [&b"exa_mple.com"[..], b"example.com\n", b"ex ample.com"] {
+            let buf = encoded_domain(name);
+            let err = Address::decode_slice_limited(&buf, &limits).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidAddress);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    #[allow(deprecated)]
+    fn lookup_resolves_through_the_function() {
+        use futures_lite::future::block_on;
+
+        let resolved: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let found = |name: &[u8], port| {
+            assert_eq!(name, b"example.com");
+            async move { Ok::<_, &str>(SocketAddr::new(resolved.ip(), port)) }
+        };
+        let addr = Address::from_domain("example.com", 80).unwrap();
+        assert_eq!(block_on(addr.lookup(found)).unwrap(), resolved);
+
+        let failing = |_: &[u8], _| async { Err::<SocketAddr, _>("no such host") };
+        let err = block_on(addr.lookup(failing)).unwrap_err();
+        assert_eq!(err.reply, Replies::HostUnreachable);
+
+        let socket = Address::from(resolved);
+        assert_eq!(block_on(socket.lookup(failing)).unwrap(), resolved);
+    }
+
+    #[test]
+    fn redacted_masks_identifying_parts() {
+        let cases = [
+            ("203.0.113.7:443", "203.0.113.xxx:443"),
+            (
+                "[2001:db8:1:2:3:4:5:6]:443",
+                "[2001:db8:1:2:xxxx:xxxx:xxxx:xxxx]:443",
+            ),
+            ("[::1]:80", "[0:0:0:0:xxxx:xxxx:xxxx:xxxx]:80"),
+        ];
+        for (addr, redacted) in cases {
+            let addr = Address::socket_from_str(addr).unwrap();
+            assert_eq!(format!("{}", addr.redacted()), redacted);
+        }
+        let cases = [
+            ("example.com", "ex…le.com:443"),
+            ("www.example.com", "ww…le.com:443"),
+            ("abc.com", "….com:443"),
+            ("abcd.com", "….com:443"),
+            ("abcde.com", "ab…de.com:443"),
+            ("localhost", "lo…st:443"),
+            ("host", "…:443"),
+        ];
+        for (name, redacted) in cases {
+            let addr = Address::from_domain(name, 443).unwrap();
+            assert_eq!(format!("{}", addr.redacted()), redacted, "{name}");
+        }
+    }
+
+    #[test]
+    fn domain_str_reports_invalid_utf8() {
+        // 0xc3 starts a two-byte sequence, 0x28 is no continuation byte
+        let name = b"exa\xc3\x28mple.com";
+        let (addr, _) = Address::decode_slice(&encoded_domain(name)).unwrap();
+        let err = addr.domain_str().unwrap().unwrap_err();
+        assert_eq!(err.valid_up_to(), 3);
+        let addr = Address::from_domain("exämple.com", 80).unwrap();
+        assert_eq!(addr.domain_str(), Some(Ok("exämple.com")));
+        assert_eq!(Address::unspecified_v4(0).domain_str(), None);
+    }
+
+    #[test]
+    fn unspecified_addresses_encode_to_zeros() {
+        let v4 = Address::unspecified_v4(0);
+        assert_eq!(v4.encode()[..], [0x01, 0, 0, 0, 0, 0, 0]);
+        assert!(v4.is_unspecified());
+        let v6 = Address::unspecified_v6(1080);
+        let mut wire = alloc::vec![0x04];
+        wire.extend_from_slice(&[0; 16]);
+        wire.extend_from_slice(&[0x04, 0x38]);
+        assert_eq!(v6.encode()[..], wire[..]);
+        assert!(v6.is_unspecified());
+        assert!(!Address::from_domain("example.com", 0)
+            .unwrap()
+            .is_unspecified());
+    }
+
+    #[test]
+    #[cfg(feature = "idna")]
+    fn from_host_encodes_international_names() {
+        let addr = Address::from_host("münchen.de", 443).unwrap();
+        assert_eq!(addr.domain_str(), Some(Ok("xn--mnchen-3ya.de")));
+        assert_eq!(
+            addr,
+            Address::from_domain("xn--mnchen-3ya.de", 443).unwrap()
+        );
+        let ascii = Address::from_host("Example.com", 80).unwrap();
+        assert_eq!(ascii.domain_str(), Some(Ok("Example.com")));
+        let ip = Address::from_host("192.0.2.1", 80).unwrap();
+        assert_eq!(ip, Address::socket_from_str("192.0.2.1:80").unwrap());
+    }
+
+    /// Resolver answering every name with `result`, counting the lookups
+    #[cfg(feature = "std")]
+    struct Fixed {
+        result: Result<[u8; 4], io::ErrorKind>,
+        lookups: core::cell::Cell<usize>,
+    }
+
+    #[cfg(feature = "std")]
+    impl Fixed {
+        fn new(result: Result<[u8; 4], io::ErrorKind>) -> Fixed {
+            let lookups = core::cell::Cell::new(0);
+            Fixed { result, lookups }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Resolver for Fixed {
+        async fn resolve(&self, _host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.lookups.set(self.lookups.get() + 1);
+            match self.result {
+                Ok(ip) => Ok(alloc::vec![SocketAddr::from((ip, port))]),
+                Err(kind) => Err(kind.into()),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn fallback_stops_at_not_found_and_skips_other_errors() {
+        use futures_lite::future::block_on;
+
+        let found = SocketAddr::from(([192, 0, 2, 1], 80));
+        let resolver = FallbackResolver::new(
+            Fixed::new(Err(io::ErrorKind::NotFound)),
+            Fixed::new(Ok([192, 0, 2, 1])),
+        );
+        let err = block_on(resolver.resolve("example.com", 80)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolver.second.lookups.get(), 0);
+
+        // nested, other errors fall through to the last resolver
+        let resolver = FallbackResolver::new(
+            Fixed::new(Err(io::ErrorKind::TimedOut)),
+            FallbackResolver::new(
+                Fixed::new(Err(io::ErrorKind::ConnectionRefused)),
+                Fixed::new(Ok([192, 0, 2, 1])),
+            ),
+        );
+        let addrs = block_on(resolver.resolve("example.com", 80)).unwrap();
+        assert_eq!(addrs, [found]);
+        assert_eq!(resolver.first.lookups.get(), 1);
+        assert_eq!(resolver.second.first.lookups.get(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn resolver_errors_map_to_replies() {
+        use futures_lite::future::block_on;
+
+        let addr = Address::from_domain("example.com", 80).unwrap();
+        let err = block_on(addr.to_socket_addrs(&Fixed::new(Err(io::ErrorKind::NotFound))));
+        let err = err.unwrap_err();
+        assert_eq!(err.reply, Replies::HostUnreachable);
+        assert_eq!(err.address(), Some(&addr));
+        // a resolver timing out is not the destination's TTL expiring
+        for kind in [io::ErrorKind::Other, io::ErrorKind::TimedOut] {
+            let err = block_on(addr.to_socket_addrs(&Fixed::new(Err(kind))));
+            assert_eq!(err.unwrap_err().reply, Replies::GeneralFailure, "{kind:?}");
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Codecs of handshake messages, for `tokio_util` with the `codec` feature and
+//! for `asynchronous-codec` with the `futures-codec` feature
+
+use bytes::{Buf, BytesMut};
+
+use crate::{
+    error::{Error, ErrorKind, Result},
+    message::{Message, Phase},
+    ser::{check_version, DecodeLimits, Encode},
+};
+
+/// Longest message of the handshake, a password request with both fields of
+/// 255 bytes, including the version
+const MAX_FRAME_LEN: usize = 1 + 1 + 255 + 1 + 255;
+
+/// Decodes the messages of a handshake, and encodes messages with their version
+///
+/// The wire format alone cannot tell messages apart, so the codec decodes the
+/// message of its [`Phase`], which the caller advances with
+/// [`set_phase`](Socks5Codec::set_phase) as the handshake goes.
+#[derive(Clone, Debug)]
+pub struct Socks5Codec {
+    phase: Phase,
+    limits: DecodeLimits,
+}
+
+impl Socks5Codec {
+    pub fn new(phase: Phase) -> Socks5Codec {
+        Socks5Codec {
+            phase,
+            limits: DecodeLimits::default(),
+        }
+    }
+
+    /// Message decoded next
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+    }
+
+    /// Sets the ceilings decoded messages are checked against, and the
+    /// characters accepted in domain names
+    pub fn set_limits(&mut self, limits: DecodeLimits) {
+        self.limits = limits;
+    }
+
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        let version = match src.first() {
+            Some(version) => *version,
+            None => return Ok(None),
+        };
+        check_version(version, self.phase.version())?;
+        match Message::decode_slice_limited(&src[1..], self.phase, &self.limits) {
+            Ok((message, n)) => {
+                src.advance(n + 1);
+                Ok(Some(message))
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::Incomplete { needed } => {
+                    let max = self.limits.max_frame_len.min(MAX_FRAME_LEN);
+                    if src.len() + needed > max {
+                        let len = src.len() + needed;
+                        return Err(Error::limit_exceeded("frame length", len, max));
+                    }
+                    src.reserve(needed);
+                    Ok(None)
+                }
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(feature = "codec")]
+impl tokio_util::codec::Decoder for Socks5Codec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        self.decode_frame(src)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl<E: Encode> tokio_util::codec::Encoder<E> for Socks5Codec {
+    type Error = Error;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<()> {
+        item.validate()?;
+        item.as_bytes_into(dst);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "futures-codec")]
+impl asynchronous_codec::Decoder for Socks5Codec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        self.decode_frame(src)
+    }
+}
+
+#[cfg(feature = "futures-codec")]
+impl asynchronous_codec::Encoder for Socks5Codec {
+    type Item<'a> = Message;
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
+        item.validate()?;
+        item.as_bytes_into(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::{
+        address::Address,
+        head::{AuthenticationRequest, PasswordRequest, TcpRequestHeader},
+        message::Method,
+    };
+
+    /// Messages of a client handshake with password authentication, with the
+    /// phase each one is decoded in
+    fn handshake() -> Vec<(Phase, Message)> {
+        let methods = [Method::NONE, Method::PASSWORD];
+        let password = PasswordRequest::new(b"user", b"secret").unwrap();
+        let dest = Address::from_domain("example.com", 443).unwrap();
+        alloc::vec![
+            (
+                Phase::AuthRequest,
+                AuthenticationRequest::new(methods).into()
+            ),
+            (Phase::PasswordRequest, password.into()),
+            (Phase::Request, TcpRequestHeader::connect(dest).into()),
+        ]
+    }
+
+    /// Decodes the handshake from `chunks`, fed one after the other
+    fn decode_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Vec<Message> {
+        let mut phases = handshake().into_iter().map(|(phase, _)| phase);
+        let mut codec = Socks5Codec::new(phases.next().unwrap());
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in chunks {
+            src.extend_from_slice(chunk);
+            while let Some(message) = codec.decode_frame(&mut src).unwrap() {
+                decoded.push(message);
+                if let Some(phase) = phases.next() {
+                    codec.set_phase(phase);
+                }
+            }
+        }
+        assert!(src.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn decodes_input_split_at_every_byte() {
+        let messages: Vec<Message> = handshake().into_iter().map(|(_, m)| m).collect();
+        let wire: Vec<u8> = messages.iter().flat_map(|m| m.as_bytes()).collect();
+        for i in 0..=wire.len() {
+            let (head, tail) = wire.split_at(i);
+            assert_eq!(decode_chunks([head, tail]), messages, "split at {i}");
+        }
+        assert_eq!(decode_chunks(wire.chunks(1)), messages);
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let mut codec = Socks5Codec::new(Phase::Request);
+        let limits = DecodeLimits {
+            max_domain_len: 8,
+            ..DecodeLimits::default()
+        };
+        codec.set_limits(limits);
+        let dest = Address::from_domain("example.com", 443).unwrap();
+        let mut src = BytesMut::from(&TcpRequestHeader::connect(dest).as_bytes()[..]);
+        let err = codec.decode_frame(&mut src).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+    }
+
+    #[cfg(feature = "futures-codec")]
+    mod framed {
+        use core::{
+            pin::Pin,
+            task::{Context, Poll},
+        };
+        use std::io;
+
+        use asynchronous_codec::Framed;
+        use futures_lite::{
+            future::{block_on, zip},
+            AsyncRead, AsyncWrite, StreamExt,
+        };
+        use futures_util::SinkExt;
+
+        use super::*;
+        use crate::head::{AuthenticationResponse, PasswordResponse, TcpResponseHeader};
+
+        /// One end of an in-memory stream
+        struct Duplex {
+            reader: piper::Reader,
+            writer: piper::Writer,
+        }
+
+        fn duplex() -> (Duplex, Duplex) {
+            let (client_reader, server_writer) = piper::pipe(64);
+            let (server_reader, client_writer) = piper::pipe(64);
+            let client = Duplex {
+                reader: client_reader,
+                writer: client_writer,
+            };
+            let server = Duplex {
+                reader: server_reader,
+                writer: server_writer,
+            };
+            (client, server)
+        }
+
+        impl AsyncRead for Duplex {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.reader).poll_read(cx, buf)
+            }
+        }
+
+        impl AsyncWrite for Duplex {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.writer).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.writer).poll_flush(cx)
+            }
+
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.writer).poll_close(cx)
+            }
+        }
+
+        #[test]
+        fn framed_round_trip() {
+            let (client, server) = duplex();
+            let mut client = Framed::new(client, Socks5Codec::new(Phase::AuthResponse));
+            let mut server = Framed::new(server, Socks5Codec::new(Phase::AuthRequest));
+            let (phases, requests): (Vec<Phase>, Vec<Message>) = handshake().into_iter().unzip();
+            let responses: Vec<(Phase, Message)> = alloc::vec![
+                (
+                    Phase::AuthResponse,
+                    AuthenticationResponse::from(Method::PASSWORD).into()
+                ),
+                (Phase::PasswordResponse, PasswordResponse::success().into()),
+                (
+                    Phase::Response,
+                    TcpResponseHeader::success(Address::unspecified_v4(1080)).into()
+                ),
+            ];
+            let client_side = async {
+                let mut received = Vec::new();
+                for (request, (phase, _)) in requests.iter().zip(&responses) {
+                    client.codec_mut().set_phase(*phase);
+                    client.send(request.clone()).await.unwrap();
+                    received.push(client.next().await.unwrap().unwrap());
+                }
+                received
+            };
+            let server_side = async {
+                let mut received = Vec::new();
+                for (phase, (_, response)) in phases.iter().zip(&responses) {
+                    server.codec_mut().set_phase(*phase);
+                    received.push(server.next().await.unwrap().unwrap());
+                    server.send(response.clone()).await.unwrap();
+                }
+                received
+            };
+            let (client_received, server_received) = block_on(zip(client_side, server_side));
+            assert_eq!(server_received, requests);
+            let responses: Vec<Message> = responses.into_iter().map(|(_, m)| m).collect();
+            assert_eq!(client_received, responses);
+        }
+    }
+}
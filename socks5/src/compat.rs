@@ -0,0 +1,120 @@
+//! Adapters for tokio I/O types, enabled by the `tokio` feature
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use tokio::io::ReadBuf;
+
+use crate::{
+    error::Result,
+    ser::{Decode, DecodeSlice},
+};
+
+/// Wraps a tokio reader or writer so that it can be used where a futures-io one
+/// is expected, such as [`Decode`] and [`Encode::write_to`](crate::ser::Encode::write_to)
+#[derive(Debug)]
+pub struct TokioIo<T>(pub T);
+
+impl<T> TokioIo<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: tokio::io::AsyncRead + Unpin> AsyncRead for TokioIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(out);
+        ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl<T: tokio::io::AsyncWrite + Unpin> AsyncWrite for TokioIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Counterpart of [`Decode`] over tokio readers
+pub trait DecodeTokio<R: tokio::io::AsyncRead + Unpin>: Sized {
+    fn decode(r: &mut R) -> impl Future<Output = Result<Self>>;
+
+    /// Reads the version, then the message
+    fn read(r: &mut R) -> impl Future<Output = Result<Self>>;
+}
+
+impl<R: tokio::io::AsyncRead + Unpin, D: DecodeSlice> DecodeTokio<R> for D {
+    async fn decode(r: &mut R) -> Result<Self> {
+        <D as Decode<_>>::decode(&mut TokioIo(r)).await
+    }
+
+    async fn read(r: &mut R) -> Result<Self> {
+        <D as Decode<_>>::read(&mut TokioIo(r)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::{block_on, zip};
+    use tokio::io::duplex;
+
+    use super::DecodeTokio;
+    use crate::{
+        address::Address,
+        head::{
+            AuthenticationRequest, AuthenticationResponse, TcpRequestHeader, TcpResponseHeader,
+        },
+        message::Method,
+        ser::Encode,
+    };
+
+    #[test]
+    fn handshake_over_tokio_streams() {
+        let (mut client, mut server) = duplex(64);
+        let dest = Address::from_domain("example.com", 443).unwrap();
+        let bound = Address::unspecified_v4(1080);
+        let client_side = async {
+            let auth_req = AuthenticationRequest::new([Method::NONE]);
+            auth_req.write_to_tokio(&mut client).await.unwrap();
+            let auth_resp = AuthenticationResponse::read(&mut client).await.unwrap();
+            assert_eq!(auth_resp.method(), Method::NONE);
+            let request = TcpRequestHeader::connect(dest.clone());
+            request.write_to_tokio(&mut client).await.unwrap();
+            TcpResponseHeader::read(&mut client).await.unwrap()
+        };
+        let server_side = async {
+            let auth_req = AuthenticationRequest::read(&mut server).await.unwrap();
+            assert_eq!(auth_req.methods(), [Method::NONE]);
+            let auth_resp = AuthenticationResponse::from(Method::NONE);
+            auth_resp.write_to_tokio(&mut server).await.unwrap();
+            let request = TcpRequestHeader::read(&mut server).await.unwrap();
+            let response = TcpResponseHeader::success(bound.clone());
+            response.write_to_tokio(&mut server).await.unwrap();
+            request
+        };
+        let (response, request) = block_on(zip(client_side, server_side));
+        assert_eq!(request.address(), &dest);
+        assert_eq!(response, TcpResponseHeader::success(bound));
+    }
+}
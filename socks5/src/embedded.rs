@@ -0,0 +1,71 @@
+//! Reading over `embedded-io-async` streams, enabled by the `embedded-io-async`
+//! feature, for use on embedded targets without `std`
+//!
+//! Messages are written by [`Encode::write_to_embedded`](crate::ser::Encode::write_to_embedded).
+
+use core::future::Future;
+
+use alloc::format;
+use embedded_io_async::{Read, ReadExactError, Write};
+
+use crate::{
+    error::{Error, ErrorKind, Result},
+    message::Replies,
+    ser::{check_version, DecodeBuf, DecodeSlice, Step},
+};
+
+/// Counterpart of [`Decode`](crate::ser::Decode) over `embedded-io-async` readers
+pub trait DecodeEmbedded<R: Read>: Sized {
+    fn decode(r: &mut R) -> impl Future<Output = Result<Self>>;
+
+    /// Reads the version, then the message
+    fn read(r: &mut R) -> impl Future<Output = Result<Self>>;
+}
+
+impl<R: Read, D: DecodeSlice> DecodeEmbedded<R> for D {
+    async fn decode(r: &mut R) -> Result<Self> {
+        let mut buf = DecodeBuf::new();
+        loop {
+            match buf.decode()? {
+                Step::Done(message) => return Ok(message),
+                Step::Missing(missing) => r.read_exact(missing).await.map_err(read_error)?,
+            }
+        }
+    }
+
+    async fn read(r: &mut R) -> Result<Self> {
+        let mut version = [0; 1];
+        r.read_exact(&mut version).await.map_err(|e| match e {
+            ReadExactError::UnexpectedEof => Error::disconnected(),
+            e => read_error(e),
+        })?;
+        check_version(version[0], D::VERSION)?;
+        <D as DecodeEmbedded<R>>::decode(r).await
+    }
+}
+
+/// Writes `buf` to `w` and flushes it, for [`Encode::write_to_embedded`](crate::ser::Encode::write_to_embedded)
+pub(crate) async fn write_all<W: Write>(w: &mut W, buf: &[u8]) -> Result<()> {
+    w.write_all(buf).await.map_err(error)?;
+    w.flush().await.map_err(error)?;
+    Ok(())
+}
+
+fn read_error<E: embedded_io_async::Error>(e: ReadExactError<E>) -> Error {
+    match e {
+        ReadExactError::UnexpectedEof => Error::with_kind(
+            ErrorKind::Io,
+            Replies::GeneralFailure,
+            "unexpected end of stream",
+        ),
+        ReadExactError::Other(e) => error(e),
+    }
+}
+
+fn error<E: embedded_io_async::Error>(e: E) -> Error {
+    Error::with_kind(
+        ErrorKind::Io,
+        Replies::GeneralFailure,
+        format!("io error: {e:?}"),
+    )
+}
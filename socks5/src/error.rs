@@ -1,58 +1,362 @@
-use std::{
+use alloc::{borrow::Cow, boxed::Box, format, string::ToString, sync::Arc};
+use core::{
     array::TryFromSliceError,
-    fmt::{Debug, Display, Formatter},
+    fmt::{Arguments, Debug, Display, Formatter},
     net::AddrParseError,
 };
 
-use crate::message::Replies;
+use crate::{address::Address, message::Replies};
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Error category, to tell failures apart without matching on messages
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The input ends before a complete message, at least `needed` more bytes are required
+    Incomplete { needed: usize },
+    /// The peer did not send a complete message in time
+    Timeout,
+    /// The message starts with this version instead of the expected one, such as
+    /// `0x04` from a SOCKS4 client or an ASCII letter from an HTTP client
+    WrongVersion(u8),
+    /// The peer closed the connection before sending anything, such as a port
+    /// scanner; unlike an end of stream within a message, this is no protocol error
+    Disconnected,
+    /// A decoded size exceeds its ceiling of [`DecodeLimits`](crate::ser::DecodeLimits)
+    LimitExceeded,
+    /// Reading or writing the stream failed, the I/O error is the source if any
+    Io,
+    /// The message is not valid, such as a request offering no method
+    Malformed,
+    /// Unknown METHOD value
+    UnsupportedMethod(u8),
+    /// Unknown CMD value
+    UnsupportedCommand(u8),
+    /// Unknown ATYP value
+    UnsupportedAddressType(u8),
+    /// A domain name or address that cannot be used, such as an empty domain name
+    InvalidAddress,
+    /// A domain name longer than the 255 bytes its length field can hold
+    AddressTooLong,
+    /// A value cannot be encoded, such as a username longer than 255 bytes
+    InvalidInput,
+    /// The peer answered with this failure reply
+    Reply(Replies),
+    /// Any other error, described by its reply code and message
+    Other,
+}
 
 #[derive(Clone)]
 pub struct Error {
     /// Reply code
     pub reply: Replies,
-    /// Error message
-    message: String,
+    kind: ErrorKind,
+    /// Error message, static ones are not allocated
+    message: Cow<'static, str>,
+    /// Underlying error, shared so that the error stays cloneable
+    source: Option<Arc<dyn core::error::Error + Send + Sync>>,
+    /// Destination the error is about
+    address: Option<Box<Address>>,
 }
 
 impl Error {
-    pub fn new<S: ToString>(reply: Replies, message: S) -> Error {
+    /// Error with a message, a `&'static str` is kept without allocating
+    ///
+    /// Takes a `&'static str` or a `String` since 0.5, other `Display` messages
+    /// go through [`Error::new_fmt`] with `format_args!("{message}")`.
+    pub fn new<M: Into<Cow<'static, str>>>(reply: Replies, message: M) -> Error {
+        Error::with_kind(ErrorKind::Other, reply, message)
+    }
+
+    /// Error with a formatted message, `format_args!` without arguments does not
+    /// allocate
+    pub fn new_fmt(reply: Replies, message: Arguments) -> Error {
+        match message.as_str() {
+            Some(message) => Error::new(reply, message),
+            None => Error::new(reply, message.to_string()),
+        }
+    }
+
+    pub(crate) fn with_kind<M>(kind: ErrorKind, reply: Replies, message: M) -> Error
+    where
+        M: Into<Cow<'static, str>>,
+    {
         Error {
             reply,
-            message: message.to_string(),
+            kind,
+            message: message.into(),
+            source: None,
+            address: None,
         }
     }
+
+    /// Attaches the underlying error, returned by `source`
+    pub fn with_source<E>(mut self, source: E) -> Error
+    where
+        E: core::error::Error + Send + Sync + 'static,
+    {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Attaches the destination the error is about, shown before the message
+    pub fn with_address(mut self, address: Address) -> Error {
+        self.address = Some(Box::new(address));
+        self
+    }
+
+    pub(crate) fn incomplete(needed: usize) -> Error {
+        Error::with_kind(
+            ErrorKind::Incomplete { needed },
+            Replies::GeneralFailure,
+            format!("incomplete message, {needed} more bytes needed"),
+        )
+    }
+
+    pub(crate) fn wrong_version(version: u8) -> Error {
+        Error::with_kind(
+            ErrorKind::WrongVersion(version),
+            Replies::ConnectionRefused,
+            format!("unsupported socks version {version:#x}"),
+        )
+    }
+
+    #[cfg(any(feature = "std", feature = "embedded-io-async"))]
+    pub(crate) fn disconnected() -> Error {
+        Error::with_kind(
+            ErrorKind::Disconnected,
+            Replies::GeneralFailure,
+            "peer disconnected",
+        )
+    }
+
+    pub(crate) fn limit_exceeded(what: &str, len: usize, limit: usize) -> Error {
+        Error::with_kind(
+            ErrorKind::LimitExceeded,
+            Replies::GeneralFailure,
+            format!("{what} of {len} exceeds the limit of {limit}"),
+        )
+    }
+
+    pub(crate) fn malformed<M: Into<Cow<'static, str>>>(message: M) -> Error {
+        Error::with_kind(ErrorKind::Malformed, Replies::GeneralFailure, message)
+    }
+
+    pub(crate) fn invalid_address<M: Into<Cow<'static, str>>>(message: M) -> Error {
+        Error::with_kind(
+            ErrorKind::InvalidAddress,
+            Replies::AddressTypeNotSupported,
+            message,
+        )
+    }
+
+    pub(crate) fn invalid_input<M: Into<Cow<'static, str>>>(message: M) -> Error {
+        Error::with_kind(ErrorKind::InvalidInput, Replies::GeneralFailure, message)
+    }
+
+    /// Timeout error, replied with [`Replies::TtlExpired`]
+    pub fn timeout() -> Error {
+        Error::with_kind(
+            ErrorKind::Timeout,
+            Replies::TtlExpired,
+            "timed out reading message",
+        )
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Error message, without the address shown before it by `Display`
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Destination the error is about, if known
+    pub fn address(&self) -> Option<&Address> {
+        self.address.as_deref()
+    }
+
+    /// Whether decoding failed only because the input is truncated, so that it
+    /// can be retried once more bytes are available
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.kind, ErrorKind::Incomplete { .. })
+    }
+
+    /// Whether the peer closed the connection cleanly before a message, see
+    /// [`ErrorKind::Disconnected`]
+    pub fn is_disconnected(&self) -> bool {
+        self.kind == ErrorKind::Disconnected
+    }
 }
 
 impl Debug for Error {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        Display::fmt(self, f)
     }
 }
 
 impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match &self.address {
+            Some(address) => write!(f, "{address}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
     }
 }
 
+/// Errors are equal when their reply code and kind are; the message, source and
+/// address are not compared
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        self.reply == other.reply && self.kind == other.kind
+    }
+}
+
+impl Eq for Error {}
+
 impl From<TryFromSliceError> for Error {
     fn from(err: TryFromSliceError) -> Error {
-        Error::new(Replies::GeneralFailure, err.to_string())
+        Error::malformed(err.to_string()).with_source(err)
     }
 }
 
 impl From<AddrParseError> for Error {
     fn from(err: AddrParseError) -> Error {
-        Error::new(Replies::GeneralFailure, err.to_string())
+        let message = err.to_string();
+        Error::with_kind(ErrorKind::InvalidAddress, Replies::GeneralFailure, message)
+            .with_source(err)
     }
 }
 
-impl std::error::Error for Error {}
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        let source: &(dyn core::error::Error + 'static) = self.source.as_deref()?;
+        Some(source)
+    }
+}
 
+/// The reply follows the kind of the I/O error, such as
+/// [`Replies::ConnectionRefused`] for a refused connection
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
-        Error::new(Replies::GeneralFailure, err.to_string())
+        Error::with_kind(ErrorKind::Io, Replies::from(&err), err.to_string()).with_source(err)
+    }
+}
+
+/// The error is kept as the inner error, recovered with `downcast_ref::<Error>()`
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> std::io::Error {
+        std::io::Error::new(err.io_kind(), err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error {
+    /// I/O error kind matching the kind of this error, or its reply
+    fn io_kind(&self) -> std::io::ErrorKind {
+        use std::io;
+
+        let source = self.source.as_deref();
+        if let Some(err) = source.and_then(|e| e.downcast_ref::<io::Error>()) {
+            return err.kind();
+        }
+        match self.kind {
+            ErrorKind::Incomplete { .. } | ErrorKind::Disconnected => io::ErrorKind::UnexpectedEof,
+            ErrorKind::Timeout => io::ErrorKind::TimedOut,
+            ErrorKind::WrongVersion(_)
+            | ErrorKind::LimitExceeded
+            | ErrorKind::Malformed
+            | ErrorKind::UnsupportedMethod(_)
+            | ErrorKind::UnsupportedCommand(_)
+            | ErrorKind::UnsupportedAddressType(_) => io::ErrorKind::InvalidData,
+            ErrorKind::InvalidAddress | ErrorKind::AddressTooLong | ErrorKind::InvalidInput => {
+                io::ErrorKind::InvalidInput
+            }
+            _ => match self.reply {
+                Replies::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+                Replies::HostUnreachable => io::ErrorKind::HostUnreachable,
+                Replies::NetworkUnreachable => io::ErrorKind::NetworkUnreachable,
+                Replies::ConnectionNotAllowed => io::ErrorKind::PermissionDenied,
+                Replies::TtlExpired => io::ErrorKind::TimedOut,
+                _ => io::ErrorKind::Other,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn io_error_keeps_its_reply() {
+        use crate::{head::TcpResponseHeader, ser::Encode};
+
+        let err = Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(err.reply, Replies::ConnectionRefused);
+        assert_eq!(err.kind(), ErrorKind::Io);
+        let response = TcpResponseHeader::failure(err.reply).as_bytes();
+        assert_eq!(response[1], 0x05);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn io_error_wraps_the_error() {
+        let err = Error::from(Replies::HostUnreachable);
+        let io_err = std::io::Error::from(err.clone());
+        assert_eq!(io_err.kind(), std::io::ErrorKind::HostUnreachable);
+        let inner = io_err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(inner.reply, Replies::HostUnreachable);
+        assert_eq!(inner.message(), err.message());
+
+        let timeout = std::io::Error::from(Error::timeout());
+        assert_eq!(timeout.kind(), std::io::ErrorKind::TimedOut);
+        let inner = timeout.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert_eq!(inner.reply, Replies::TtlExpired);
+    }
+
+    #[test]
+    fn errors_compare_by_reply_and_kind() {
+        let err = Error::from(Replies::HostUnreachable).with_address(Address::unspecified_v4(80));
+        assert_eq!(err, Error::from(Replies::HostUnreachable));
+        assert_ne!(err, Error::from(Replies::NetworkUnreachable));
+        // same reply, another kind
+        let other = Error::new(Replies::HostUnreachable, "no route");
+        assert_ne!(other, Error::from(Replies::HostUnreachable));
+        assert_eq!(
+            other,
+            Error::new(Replies::HostUnreachable, "another message")
+        );
+        assert_eq!(
+            Error::timeout(),
+            Error::timeout().with_source(Error::wrong_version(4))
+        );
+    }
+
+    #[test]
+    fn new_displays_its_message() {
+        let err = Error::new(Replies::GeneralFailure, "static text");
+        assert!(matches!(err.message, Cow::Borrowed("static text")));
+        assert_eq!(err.to_string(), "static text");
+        let err = Error::new(Replies::GeneralFailure, format!("{} bytes", 42));
+        assert_eq!(err.to_string(), "42 bytes");
+        let err = err.with_address(Address::unspecified_v4(80));
+        assert_eq!(err.to_string(), "0.0.0.0:80: 42 bytes");
+    }
+
+    #[test]
+    fn new_fmt_displays_its_message() {
+        let err = Error::new_fmt(Replies::GeneralFailure, format_args!("static text"));
+        assert!(matches!(err.message, Cow::Borrowed("static text")));
+        assert_eq!(err.to_string(), "static text");
+        let port = 1080;
+        let err = Error::new_fmt(Replies::GeneralFailure, format_args!("port {port}"));
+        assert!(matches!(err.message, Cow::Owned(_)));
+        assert_eq!(err.to_string(), "port 1080");
     }
 }
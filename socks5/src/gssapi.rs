@@ -0,0 +1,263 @@
+//! GSSAPI authentication (RFC 1961)
+//!
+//! Only the context establishment is driven here, the security mechanism itself
+//! is provided by a [`GssContext`]. Per-message protection is not negotiated, so
+//! the connection carries unprotected data once authenticated.
+
+use alloc::{format, vec::Vec};
+
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    error::{Error, Result},
+    head::{GssapiMessage, GssapiMessageType},
+    message::Replies,
+    ser::{read_counted, Encode},
+};
+
+/// Security context of one side of the exchange, such as a Kerberos
+/// initiator or acceptor
+pub trait GssContext {
+    /// Processes a token received from the peer and returns the token to send
+    /// back, if any
+    ///
+    /// An initiator is first called with an empty token.
+    fn step(&mut self, token: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Whether the context is established, ending the exchange
+    fn is_established(&self) -> bool;
+}
+
+/// Exchanges tokens with the peer until `context` is established
+///
+/// The initiator (client) sends the first token. If `context` fails, an abort
+/// message is sent to the peer before the error is returned. Returns the number
+/// of bytes read from and written to the peer.
+pub async fn establish<T>(
+    context: &mut dyn GssContext,
+    stream: &mut T,
+    initiator: bool,
+) -> Result<(usize, usize)>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let (mut read, mut written) = (0, 0);
+    let mut token = if initiator {
+        step(context, stream, &[]).await?
+    } else {
+        None
+    };
+    loop {
+        if let Some(token) = token {
+            let message = GssapiMessage::new(GssapiMessageType::Authentication, token)?;
+            message.write_to(stream).await?;
+            written += message.encoded_len() + 1;
+        }
+        if context.is_established() {
+            return Ok((read, written));
+        }
+        let (message, n) = read_counted::<GssapiMessage, _>(stream).await?;
+        read += n;
+        match message.message_type() {
+            GssapiMessageType::Authentication => {}
+            GssapiMessageType::Abort => {
+                return Err(Error::new(
+                    Replies::ConnectionNotAllowed,
+                    "gssapi authentication aborted by peer",
+                ))
+            }
+            t => {
+                return Err(Error::malformed(format!(
+                    "unexpected gssapi message type {t:?}"
+                )))
+            }
+        }
+        token = step(context, stream, message.token()).await?;
+    }
+}
+
+async fn step<T>(
+    context: &mut dyn GssContext,
+    stream: &mut T,
+    token: &[u8],
+) -> Result<Option<Vec<u8>>>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    match context.step(token) {
+        Ok(token) => Ok(token),
+        Err(e) => {
+            GssapiMessage::abort().write_to(stream).await?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::VecDeque, vec};
+    use core::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use std::io;
+
+    use futures_lite::{
+        future::{block_on, zip},
+        AsyncRead, AsyncWrite,
+    };
+
+    use super::*;
+    use crate::error::ErrorKind;
+
+    /// Context returning the tokens of its script in turn, established once
+    /// they are all returned, failing at step `fail_at` if set
+    struct Scripted {
+        script: VecDeque<Option<Vec<u8>>>,
+        fail_at: Option<usize>,
+        received: Vec<Vec<u8>>,
+    }
+
+    impl Scripted {
+        fn new(script: &[Option<&[u8]>]) -> Scripted {
+            Scripted {
+                script: script
+                    .iter()
+                    .map(|token| token.map(<[u8]>::to_vec))
+                    .collect(),
+                fail_at: None,
+                received: Vec::new(),
+            }
+        }
+    }
+
+    impl GssContext for Scripted {
+        fn step(&mut self, token: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.received.push(token.to_vec());
+            if self.fail_at == Some(self.received.len()) {
+                return Err(Error::new(Replies::GeneralFailure, "defective credentials"));
+            }
+            Ok(self.script.pop_front().expect("step past the script"))
+        }
+
+        fn is_established(&self) -> bool {
+            self.script.is_empty()
+        }
+    }
+
+    /// One end of an in-memory stream
+    struct Duplex {
+        reader: piper::Reader,
+        writer: piper::Writer,
+    }
+
+    fn duplex() -> (Duplex, Duplex) {
+        let (client_reader, server_writer) = piper::pipe(256);
+        let (server_reader, client_writer) = piper::pipe(256);
+        let client = Duplex {
+            reader: client_reader,
+            writer: client_writer,
+        };
+        let server = Duplex {
+            reader: server_reader,
+            writer: server_writer,
+        };
+        (client, server)
+    }
+
+    impl AsyncRead for Duplex {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.reader).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Duplex {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.writer).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.writer).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.writer).poll_close(cx)
+        }
+    }
+
+    /// Initiator of two round trips, the second answer of the acceptor ending it
+    fn initiator() -> Scripted {
+        Scripted::new(&[Some(b"init-1"), Some(b"init-2"), None])
+    }
+
+    fn acceptor() -> Scripted {
+        Scripted::new(&[Some(b"accept-1"), Some(b"accept-2")])
+    }
+
+    #[test]
+    fn exchange_runs_until_both_are_established() {
+        let (mut client, mut server) = duplex();
+        let (mut initiator, mut acceptor) = (initiator(), acceptor());
+        let (initiated, accepted) = block_on(zip(
+            establish(&mut initiator, &mut client, true),
+            establish(&mut acceptor, &mut server, false),
+        ));
+        // each message is the 4 bytes of its header and the token
+        assert_eq!(initiated.unwrap(), (2 * 4 + 16, 2 * 4 + 12));
+        assert_eq!(accepted.unwrap(), (2 * 4 + 12, 2 * 4 + 16));
+        assert_eq!(initiator.received, [&b""[..], b"accept-1", b"accept-2"]);
+        assert_eq!(acceptor.received, [b"init-1", b"init-2"]);
+        assert!(initiator.is_established() && acceptor.is_established());
+    }
+
+    #[test]
+    fn failed_step_aborts_the_peer() {
+        let (mut client, mut server) = duplex();
+        let (mut initiator, mut acceptor) = (initiator(), acceptor());
+        acceptor.fail_at = Some(2);
+        let (initiated, accepted) = block_on(zip(
+            establish(&mut initiator, &mut client, true),
+            establish(&mut acceptor, &mut server, false),
+        ));
+        let err = accepted.unwrap_err();
+        assert_eq!(err.message(), "defective credentials");
+        let err = initiated.unwrap_err();
+        assert_eq!(err.reply, Replies::ConnectionNotAllowed);
+        assert_eq!(err.message(), "gssapi authentication aborted by peer");
+        assert_eq!(initiator.received.len(), 2);
+    }
+
+    #[test]
+    fn failed_first_step_aborts_before_any_token() {
+        let (mut client, mut server) = duplex();
+        let (mut initiator, mut acceptor) = (initiator(), acceptor());
+        initiator.fail_at = Some(1);
+        let (initiated, accepted) = block_on(zip(
+            establish(&mut initiator, &mut client, true),
+            establish(&mut acceptor, &mut server, false),
+        ));
+        assert_eq!(initiated.unwrap_err().message(), "defective credentials");
+        assert_eq!(accepted.unwrap_err().reply, Replies::ConnectionNotAllowed);
+        assert!(acceptor.received.is_empty());
+    }
+
+    #[test]
+    fn unexpected_message_type_is_malformed() {
+        let (mut client, mut server) = duplex();
+        let mut acceptor = acceptor();
+        let peer = async {
+            let message = GssapiMessage::new(GssapiMessageType::Protection, vec![0]).unwrap();
+            message.write_to(&mut client).await.unwrap();
+        };
+        let (accepted, ()) = block_on(zip(establish(&mut acceptor, &mut server, false), peer));
+        assert_eq!(accepted.unwrap_err().kind(), ErrorKind::Malformed);
+    }
+}
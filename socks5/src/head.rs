@@ -1,62 +1,116 @@
-use core::convert::TryFrom;
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::{
+    convert::TryFrom,
+    fmt::{Debug, Formatter},
+};
 
-use bytes::{BufMut, Bytes, BytesMut};
-use futures_lite::AsyncReadExt;
+use bytes::{BufMut, BytesMut};
 use tinyvec::ArrayVec;
 
 use crate::{
     address::Address,
-    error::Result,
-    message::{Command, Method, Replies},
-    ser::{Decode, Encode},
+    error::{Error, Result},
+    message::{Command, Method, MethodSet, Replies},
+    ser::{DecodeLimits, DecodeSlice, Encode, SliceReader},
 };
 
 /// SOCKS5 authentication request packet
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct AuthenticationRequest {
-    methods: ArrayVec<[Method; 256]>,
+    methods: Box<ArrayVec<[Method; 256]>>,
 }
 
 impl AuthenticationRequest {
+    /// Most methods a request can offer, as counted by its one-byte NMETHODS field
+    pub const MAX_METHODS: usize = 255;
+
+    /// Request offering `methods` in order, duplicates are dropped and methods
+    /// past [`MAX_METHODS`](Self::MAX_METHODS) are ignored
+    pub fn new(methods: impl IntoIterator<Item = Method>) -> AuthenticationRequest {
+        let mut seen = MethodSet::new();
+        let methods = methods
+            .into_iter()
+            .filter(|method| seen.insert(*method))
+            .take(Self::MAX_METHODS)
+            .collect();
+        AuthenticationRequest {
+            methods: Box::new(methods),
+        }
+    }
+
     pub fn required_authentication(&self) -> bool {
-        !self.methods.contains(&Method::NONE)
+        !self.method_set().contains(Method::NONE)
+    }
+
+    /// Methods offered by the client
+    pub fn methods(&self) -> &[Method] {
+        &self.methods
+    }
+
+    /// Methods offered by the client, without duplicates
+    pub fn method_set(&self) -> MethodSet {
+        MethodSet::from(self)
     }
 }
 
-impl<T: AsyncReadExt + Unpin> Decode<T> for AuthenticationRequest {
-    async fn decode(r: &mut T) -> Result<Self> {
-        let n = Self::read_u8(r).await? as usize;
-        let mut buf = vec![0; n * Method::size_hint()];
-        r.read_exact(&mut buf).await?;
+impl DecodeSlice for AuthenticationRequest {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let n = r.u8()? as usize;
         let mut methods = ArrayVec::new();
+        if n == 0 {
+            return Err(Error::malformed("no authentication method offered"));
+        }
+        if n > methods.capacity() {
+            return Err(Error::malformed(format!(
+                "too many authentication methods: {n}"
+            )));
+        }
+        let buf = r.bytes(n * Method::size_hint())?;
         for i in buf {
-            let method = Method::try_from(i)?;
+            let method = Method::try_from(*i)?;
             methods.push(method);
         }
-        Ok(AuthenticationRequest { methods })
+        let methods = Box::new(methods);
+        Ok((AuthenticationRequest { methods }, r.consumed()))
+    }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<()> {
+        DecodeLimits::check("method count", self.methods.len(), limits.max_methods)
     }
 }
 
 impl Encode for AuthenticationRequest {
-    fn encode(&self) -> Bytes {
-        let mut buffer = BytesMut::new();
+    fn encoded_len(&self) -> usize {
+        1 + self.methods.len() * Method::size_hint()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
         buffer.put_u8(self.methods.len() as u8);
-        for i in &self.methods {
-            buffer.put_u8(*i as u8);
+        for i in self.methods.iter() {
+            buffer.put_u8(i.as_u8());
         }
-        buffer.freeze()
     }
 }
 
+/// Same as [`AuthenticationRequest::new`]: unlike earlier versions, duplicate
+/// methods are dropped, so the request may offer fewer methods than the slice
 impl<'a> From<&'a [Method]> for AuthenticationRequest {
     fn from(m: &'a [Method]) -> Self {
-        let mut methods = ArrayVec::new();
-        methods.extend_from_slice(m);
+        AuthenticationRequest::new(m.iter().copied())
+    }
+}
+
+/// Offers the methods of the set in ascending order
+impl From<MethodSet> for AuthenticationRequest {
+    fn from(set: MethodSet) -> Self {
+        let methods = Box::new(set.iter().collect());
         Self { methods }
     }
 }
 
 /// SOCKS5 authentication response packet
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct AuthenticationResponse {
     method: Method,
 }
@@ -65,21 +119,28 @@ impl AuthenticationResponse {
     pub fn required_authentication(&self) -> bool {
         self.method != Method::NONE
     }
+
+    /// Method selected by the server
+    pub fn method(&self) -> Method {
+        self.method
+    }
 }
 
-impl<T: AsyncReadExt + Unpin> Decode<T> for AuthenticationResponse {
-    async fn decode(r: &mut T) -> Result<Self> {
-        let method = Self::read_u8(r).await?;
-        let method = Method::try_from(method)?;
-        Ok(AuthenticationResponse { method })
+impl DecodeSlice for AuthenticationResponse {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let method = Method::try_from(r.u8()?)?;
+        Ok((AuthenticationResponse { method }, r.consumed()))
     }
 }
 
 impl Encode for AuthenticationResponse {
-    fn encode(&self) -> Bytes {
-        let mut buffer = BytesMut::new();
-        buffer.put_u8(self.method as u8);
-        buffer.freeze()
+    fn encoded_len(&self) -> usize {
+        Method::size_hint()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.method.as_u8());
     }
 }
 
@@ -98,6 +159,7 @@ impl From<Method> for AuthenticationResponse {
 /// | 1  |  1  | X'00' |  1   | Variable |    2     |
 /// +----+-----+-------+------+----------+----------+
 /// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TcpRequestHeader {
     /// SOCKS5 command
     command: Command,
@@ -110,6 +172,37 @@ impl TcpRequestHeader {
         Self { command, address }
     }
 
+    /// CONNECT request to the destination `address`
+    pub fn connect(address: Address) -> TcpRequestHeader {
+        TcpRequestHeader::new(Command::Connect, address)
+    }
+
+    /// BIND request, `expected_peer` is the address the incoming connection is
+    /// expected from
+    pub fn bind(expected_peer: Address) -> TcpRequestHeader {
+        TcpRequestHeader::new(Command::Bind, expected_peer)
+    }
+
+    /// UDP ASSOCIATE request, `client_hint` is the address the client will send
+    /// datagrams from, unspecified if not known yet
+    pub fn udp_associate(client_hint: Address) -> TcpRequestHeader {
+        TcpRequestHeader::new(Command::UdpAssociate, client_hint)
+    }
+
+    /// Checks that the address makes sense for the command: a CONNECT
+    /// destination must not be an unspecified IP address nor have port 0
+    pub fn check_command(&self) -> Result<()> {
+        let port = match &self.address {
+            Address::Socket(addr) => addr.port(),
+            Address::DomainName(_, port) => *port,
+        };
+        if self.command == Command::Connect && (self.address.is_unspecified() || port == 0) {
+            let message = format!("cannot connect to {}", self.address);
+            return Err(Error::invalid_address(message));
+        }
+        Ok(())
+    }
+
     pub fn address(&self) -> &Address {
         &self.address
     }
@@ -117,26 +210,54 @@ impl TcpRequestHeader {
     pub fn command(&self) -> Command {
         self.command
     }
+
+    /// Takes the command and the address, without cloning the address
+    pub fn into_parts(self) -> (Command, Address) {
+        (self.command, self.address)
+    }
+
+    pub fn into_address(self) -> Address {
+        self.address
+    }
 }
 
-impl<T: AsyncReadExt + Unpin> Decode<T> for TcpRequestHeader {
-    async fn decode(r: &mut T) -> Result<Self> {
-        let command = Self::read_u8(r).await?;
-        let command = Command::try_from(command)?;
-        Self::read_u8(r).await?;
-        let address = Address::decode(r).await?;
-        Ok(TcpRequestHeader { command, address })
+impl DecodeSlice for TcpRequestHeader {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        TcpRequestHeader::decode_slice_limited(buf, &DecodeLimits::default())
+    }
+
+    fn decode_slice_limited(buf: &[u8], limits: &DecodeLimits) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let buf = r.bytes(2)?;
+        let command = Command::try_from(buf[0])?;
+        check_reserved(buf[1])?;
+        let address = r.decode_limited(limits)?;
+        Ok((TcpRequestHeader { command, address }, r.consumed()))
+    }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<()> {
+        self.address.check_limits(limits)
     }
 }
 
 impl Encode for TcpRequestHeader {
-    fn encode(&self) -> Bytes {
-        let mut buffer = BytesMut::new();
-        buffer.put_u8(self.command as u8);
+    fn encoded_len(&self) -> usize {
+        2 + self.address.encoded_len()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.command.as_u8());
         buffer.put_u8(0);
-        buffer.put_slice(&self.address.encode());
-        buffer.freeze()
+        self.address.encode_into(buffer);
+    }
+}
+
+/// Fails unless the RSV byte of a header is `X'00'`
+fn check_reserved(rsv: u8) -> Result<()> {
+    if rsv != 0 {
+        return Err(Error::malformed(format!("reserved byte is {rsv:#04x}")));
     }
+    Ok(())
 }
 
 /// TCP response header
@@ -148,7 +269,7 @@ impl Encode for TcpRequestHeader {
 /// | 1  |  1  | X'00' |  1   | Variable |    2     |
 /// +----+-----+-------+------+----------+----------+
 /// ```
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TcpResponseHeader {
     /// SOCKS5 reply
     pub reply: Replies,
@@ -162,27 +283,539 @@ impl TcpResponseHeader {
         TcpResponseHeader { reply, address }
     }
 
+    /// Success reply carrying the address bound by the server
+    pub fn success(address: Address) -> TcpResponseHeader {
+        TcpResponseHeader::new(Replies::Succeeded, address)
+    }
+
+    /// Failure reply, with the unspecified address `0.0.0.0:0`
+    pub fn failure(reply: Replies) -> TcpResponseHeader {
+        TcpResponseHeader::new(reply, Address::unspecified_v4(0))
+    }
+
     pub fn is_success(&self) -> bool {
         self.reply == Replies::Succeeded
     }
+
+    /// Bound address (BND.ADDR)
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Takes the reply and the bound address, without cloning the address
+    pub fn into_parts(self) -> (Replies, Address) {
+        (self.reply, self.address)
+    }
+
+    pub fn into_address(self) -> Address {
+        self.address
+    }
 }
 
-impl<T: AsyncReadExt + Unpin> Decode<T> for TcpResponseHeader {
-    async fn decode(r: &mut T) -> Result<Self> {
-        let reply = Self::read_u8(r).await?;
-        let reply = Replies::try_from(reply)?;
-        Self::read_u8(r).await?;
-        let address = Address::decode(r).await?;
-        Ok(TcpResponseHeader { reply, address })
+impl DecodeSlice for TcpResponseHeader {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        TcpResponseHeader::decode_slice_limited(buf, &DecodeLimits::default())
+    }
+
+    fn decode_slice_limited(buf: &[u8], limits: &DecodeLimits) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let buf = r.bytes(2)?;
+        let reply = Replies::from(buf[0]);
+        check_reserved(buf[1])?;
+        let address = r.decode_limited(limits)?;
+        Ok((TcpResponseHeader { reply, address }, r.consumed()))
+    }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<()> {
+        self.address.check_limits(limits)
     }
 }
 
 impl Encode for TcpResponseHeader {
-    fn encode(&self) -> Bytes {
-        let mut buffer = BytesMut::new();
-        buffer.put_u8(self.reply as u8);
+    fn encoded_len(&self) -> usize {
+        2 + self.address.encoded_len()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.reply.as_u8());
         buffer.put_u8(0);
-        buffer.put_slice(&self.address.encode());
-        buffer.freeze()
+        self.address.encode_into(buffer);
+    }
+}
+
+/// Version of the username/password subnegotiation
+const PASSWORD_VERSION: u8 = 0x01;
+
+/// Username/password authentication request (RFC 1929)
+///
+/// ```plain
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PasswordRequest {
+    username: Box<ArrayVec<[u8; 256]>>,
+    password: Box<ArrayVec<[u8; 256]>>,
+}
+
+impl PasswordRequest {
+    /// Creates a request, both fields are at most 255 bytes long
+    pub fn new(username: &[u8], password: &[u8]) -> Result<PasswordRequest> {
+        if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+            return Err(Error::invalid_input(
+                "username or password longer than 255 bytes",
+            ));
+        }
+        let mut request = PasswordRequest {
+            username: Box::default(),
+            password: Box::default(),
+        };
+        request.username.extend_from_slice(username);
+        request.password.extend_from_slice(password);
+        Ok(request)
+    }
+
+    pub fn username(&self) -> &[u8] {
+        &self.username
+    }
+
+    pub fn password(&self) -> &[u8] {
+        &self.password
+    }
+}
+
+/// The password is never printed
+impl Debug for PasswordRequest {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.debug_struct("PasswordRequest")
+            .field("username", &String::from_utf8_lossy(&self.username))
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl DecodeSlice for PasswordRequest {
+    const VERSION: u8 = PASSWORD_VERSION;
+
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let n = r.u8()? as usize;
+        let username = r.bytes(n)?;
+        let n = r.u8()? as usize;
+        let password = r.bytes(n)?;
+        Ok((PasswordRequest::new(username, password)?, r.consumed()))
+    }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<()> {
+        DecodeLimits::check(
+            "username length",
+            self.username.len(),
+            limits.max_username_len,
+        )?;
+        DecodeLimits::check(
+            "password length",
+            self.password.len(),
+            limits.max_password_len,
+        )
+    }
+}
+
+impl Encode for PasswordRequest {
+    const VERSION: u8 = PASSWORD_VERSION;
+
+    fn encoded_len(&self) -> usize {
+        2 + self.username.len() + self.password.len()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.username.len() as u8);
+        buffer.put_slice(&self.username);
+        buffer.put_u8(self.password.len() as u8);
+        buffer.put_slice(&self.password);
+    }
+}
+
+/// Username/password authentication response (RFC 1929)
+///
+/// ```plain
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PasswordResponse {
+    status: u8,
+}
+
+impl PasswordResponse {
+    pub fn success() -> PasswordResponse {
+        PasswordResponse { status: 0 }
+    }
+
+    pub fn failure() -> PasswordResponse {
+        PasswordResponse { status: 1 }
+    }
+
+    /// Whether authentication succeeded, any non-zero status is a failure
+    pub fn is_success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+impl DecodeSlice for PasswordResponse {
+    const VERSION: u8 = PASSWORD_VERSION;
+
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let status = r.u8()?;
+        Ok((PasswordResponse { status }, r.consumed()))
+    }
+}
+
+impl Encode for PasswordResponse {
+    const VERSION: u8 = PASSWORD_VERSION;
+
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.status);
+    }
+}
+
+/// Reply of a SOCKS4 server, to turn SOCKS4 clients away with a reply they
+/// understand
+///
+/// ```plain
+/// +----+----+---------+-------+
+/// | VN | CD | DSTPORT | DSTIP |
+/// +----+----+---------+-------+
+/// | 1  | 1  |    2    |   4   |
+/// +----+----+---------+-------+
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Socks4Response {
+    code: u8,
+}
+
+impl Socks4Response {
+    /// Version of the first byte of SOCKS4 requests
+    pub const REQUEST_VERSION: u8 = 0x04;
+
+    /// Request rejected or failed (CD `0x5b`)
+    pub fn rejected() -> Socks4Response {
+        Socks4Response { code: 0x5b }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+}
+
+impl Encode for Socks4Response {
+    /// VN of SOCKS4 replies
+    const VERSION: u8 = 0x00;
+
+    fn encoded_len(&self) -> usize {
+        7
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.code);
+        buffer.put_u16(0);
+        buffer.put_u32(0);
+    }
+}
+
+/// Version of the GSSAPI subnegotiation
+const GSSAPI_VERSION: u8 = 0x01;
+
+/// GSSAPI message type (MTYP)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GssapiMessageType {
+    /// Context establishment token
+    Authentication = 0x01,
+    /// Per-message protection level negotiation
+    Protection = 0x02,
+    /// Encapsulated user data
+    Encapsulation = 0x03,
+    /// Sent instead of a token when context establishment fails
+    Abort = 0xff,
+}
+
+impl TryFrom<u8> for GssapiMessageType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        let t = match value {
+            0x01 => GssapiMessageType::Authentication,
+            0x02 => GssapiMessageType::Protection,
+            0x03 => GssapiMessageType::Encapsulation,
+            0xff => GssapiMessageType::Abort,
+            c => {
+                return Err(Error::malformed(format!(
+                    "unsupported gssapi message type {c:#x}"
+                )))
+            }
+        };
+        Ok(t)
+    }
+}
+
+/// GSSAPI subnegotiation message (RFC 1961)
+///
+/// ```plain
+/// +------+------+------+.......................+
+/// + ver  | mtyp | len  |       token           |
+/// +------+------+------+.......................+
+/// + 0x01 | 0x01 | 0x02 | up to 2^16 - 1 octets |
+/// +------+------+------+.......................+
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GssapiMessage {
+    message_type: GssapiMessageType,
+    token: Vec<u8>,
+}
+
+impl GssapiMessage {
+    /// Creates a message, the token is at most 65535 bytes long, and empty for
+    /// an abort message as it is not encoded
+    pub fn new(message_type: GssapiMessageType, token: Vec<u8>) -> Result<GssapiMessage> {
+        if token.len() > u16::MAX as usize {
+            return Err(Error::invalid_input("gssapi token longer than 65535 bytes"));
+        }
+        if message_type == GssapiMessageType::Abort && !token.is_empty() {
+            return Err(Error::invalid_input("gssapi abort message with a token"));
+        }
+        Ok(GssapiMessage {
+            message_type,
+            token,
+        })
+    }
+
+    /// Abort message, which carries no token
+    pub fn abort() -> GssapiMessage {
+        GssapiMessage {
+            message_type: GssapiMessageType::Abort,
+            token: Vec::new(),
+        }
+    }
+
+    pub fn message_type(&self) -> GssapiMessageType {
+        self.message_type
+    }
+
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+}
+
+impl DecodeSlice for GssapiMessage {
+    const VERSION: u8 = GSSAPI_VERSION;
+
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let message_type = GssapiMessageType::try_from(r.u8()?)?;
+        // the abort message has no length nor token
+        if message_type == GssapiMessageType::Abort {
+            return Ok((GssapiMessage::abort(), r.consumed()));
+        }
+        let len = r.bytes(2)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let token = r.bytes(len)?.to_vec();
+        let message = GssapiMessage {
+            message_type,
+            token,
+        };
+        Ok((message, r.consumed()))
+    }
+}
+
+impl Encode for GssapiMessage {
+    const VERSION: u8 = GSSAPI_VERSION;
+
+    fn encoded_len(&self) -> usize {
+        match self.message_type {
+            GssapiMessageType::Abort => 1,
+            _ => 3 + self.token.len(),
+        }
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.message_type as u8);
+        if self.message_type != GssapiMessageType::Abort {
+            buffer.put_u16(self.token.len() as u16);
+            buffer.put_slice(&self.token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+    use super::*;
+    use crate::message::Message;
+
+    #[test]
+    fn check_command_rejects_unspecified_connect() {
+        let unspecified = TcpRequestHeader::connect(Address::unspecified_v4(0));
+        assert!(unspecified.check_command().is_err());
+        let no_port =
+            TcpRequestHeader::connect(Address::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))));
+        assert!(no_port.check_command().is_err());
+        // only CONNECT needs a real destination
+        let bind = TcpRequestHeader::bind(Address::unspecified_v4(0));
+        assert!(bind.check_command().is_ok());
+    }
+
+    #[test]
+    fn encoding_does_not_check_command() {
+        let header = TcpRequestHeader::connect(Address::unspecified_v4(0));
+        assert!(Encode::validate(&header).is_ok());
+        assert!(Message::Request(header).validate().is_ok());
+    }
+
+    #[test]
+    fn request_decoding_follows_the_domain_policy() {
+        let buf = [0x01, 0x00, 0x03, 0x04, b'a', 0x00, b'\n', b'b', 0x00, 0x50];
+        assert!(TcpRequestHeader::decode_slice(&buf).is_err());
+        let limits = DecodeLimits {
+            domain_policy: crate::address::DomainPolicy::Permissive,
+            ..DecodeLimits::default()
+        };
+        let (header, n) = TcpRequestHeader::decode_slice_limited(&buf, &limits).unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(header.address().domain_str(), Some(Ok("a\0\nb")));
+    }
+
+    /// Checks that `message` encodes to `wire`, version included, and that
+    /// `wire` decodes to `message`
+    fn golden<M: Encode + DecodeSlice + PartialEq + Debug>(message: M, wire: &[u8]) {
+        assert_eq!(message.as_bytes()[..], wire[..], "{message:?}");
+        assert_eq!(wire[0], <M as DecodeSlice>::VERSION);
+        let (decoded, n) = M::decode_slice(&wire[1..]).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(n, wire.len() - 1);
+    }
+
+    fn connect(address: impl Into<Address>) -> TcpRequestHeader {
+        TcpRequestHeader::connect(address.into())
+    }
+
+    #[test]
+    fn golden_method_selection() {
+        let request = AuthenticationRequest::new([Method::NONE, Method::PASSWORD]);
+        golden(request, &[0x05, 0x02, 0x00, 0x02]);
+        golden(
+            AuthenticationResponse::from(Method::PASSWORD),
+            &[0x05, 0x02],
+        );
+        golden(
+            AuthenticationResponse::from(Method::NotAcceptable),
+            &[0x05, 0xff],
+        );
+    }
+
+    #[test]
+    fn golden_requests() {
+        golden(
+            connect(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80)),
+            &[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50],
+        );
+        let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        golden(
+            connect(SocketAddrV6::new(ip, 443, 0, 0)),
+            &[
+                0x05, 0x01, 0x00, 0x04, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0x01, 0x01, 0xbb,
+            ],
+        );
+        golden(
+            connect(Address::from_domain("example.com", 443).unwrap()),
+            &[
+                0x05, 0x01, 0x00, 0x03, 0x0b, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c',
+                b'o', b'm', 0x01, 0xbb,
+            ],
+        );
+    }
+
+    #[test]
+    fn golden_replies() {
+        let bound = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1080);
+        golden(
+            TcpResponseHeader::success(bound.into()),
+            &[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38],
+        );
+        golden(
+            TcpResponseHeader::failure(Replies::HostUnreachable),
+            &[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0x00, 0x00],
+        );
+    }
+
+    #[test]
+    fn golden_subnegotiations() {
+        golden(
+            PasswordRequest::new(b"user", b"pass").unwrap(),
+            &[
+                0x01, 0x04, b'u', b's', b'e', b'r', 0x04, b'p', b'a', b's', b's',
+            ],
+        );
+        golden(PasswordResponse::success(), &[0x01, 0x00]);
+        golden(PasswordResponse::failure(), &[0x01, 0x01]);
+        let token = GssapiMessage::new(GssapiMessageType::Authentication, alloc::vec![0xaa, 0xbb]);
+        golden(token.unwrap(), &[0x01, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+        golden(GssapiMessage::abort(), &[0x01, 0xff]);
+    }
+
+    #[test]
+    fn response_with_domain_bound_address() {
+        let wire = [
+            0x00, 0x00, 0x03, 0x09, b'p', b'r', b'o', b'x', b'y', b'.', b'l', b'a', b'n', 0x04,
+            0x38,
+        ];
+        let (response, n) = TcpResponseHeader::decode_slice(&wire).unwrap();
+        assert_eq!(n, wire.len());
+        assert!(response.is_success());
+        let bound = Address::from_domain("proxy.lan", 1080).unwrap();
+        assert_eq!(response.address(), &bound);
+        assert_eq!(response.into_address(), bound);
+    }
+
+    fn assert_value_type<T: Clone + Debug + PartialEq + Eq + core::hash::Hash>() {}
+
+    fn assert_copy_type<T: Copy + Clone + Debug + PartialEq + Eq + core::hash::Hash>() {}
+
+    #[test]
+    fn protocol_types_derive_standard_traits() {
+        assert_value_type::<AuthenticationRequest>();
+        assert_value_type::<TcpRequestHeader>();
+        assert_value_type::<TcpResponseHeader>();
+        assert_value_type::<PasswordRequest>();
+        assert_value_type::<GssapiMessage>();
+        assert_value_type::<Message>();
+        assert_value_type::<Address>();
+        assert_copy_type::<AuthenticationResponse>();
+        assert_copy_type::<PasswordResponse>();
+        assert_copy_type::<GssapiMessageType>();
+        assert_copy_type::<Method>();
+        assert_copy_type::<Command>();
+        assert_copy_type::<Replies>();
+        assert_copy_type::<crate::message::Phase>();
+        assert_copy_type::<MethodSet>();
+    }
+
+    #[test]
+    fn password_is_not_printed() {
+        let request = PasswordRequest::new(b"alice", b"hunter2").unwrap();
+        let shown = format!("{request:?}");
+        assert!(shown.contains("alice"));
+        assert!(!shown.contains("hunter2"));
     }
 }
@@ -1,11 +1,42 @@
 //! Socks5 protocol definition (RFC1928)
 //!
 //! Implements [SOCKS Protocol Version 5](https://www.ietf.org/rfc/rfc1928) proxy protocol
+//!
+//! Without the default `std` feature, the crate is `no_std` and needs `alloc`: the
+//! messages, [`ser::Encode`] and [`ser::DecodeSlice`] remain available, while
+//! everything doing I/O or name resolution is left out.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 pub mod address;
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
+#[cfg(any(feature = "codec", feature = "futures-codec"))]
+pub mod codec;
+#[cfg(feature = "tokio")]
+pub mod compat;
+#[cfg(feature = "embedded-io-async")]
+pub mod embedded;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod gssapi;
 pub mod head;
 pub mod message;
 pub mod ser;
+#[cfg(feature = "serde")]
+mod serde;
 
 const VERSION: u8 = 0x05;
+
+/// Longest frame the wire format can declare, version included: a GSSAPI
+/// message with a token of 65535 bytes
+///
+/// The default of [`DecodeLimits::max_frame_len`](ser::DecodeLimits::max_frame_len),
+/// lower that to bound the memory of a connection: a message declaring a longer
+/// length fails with [`ErrorKind::LimitExceeded`](error::ErrorKind::LimitExceeded)
+/// before it is buffered.
+pub const MAX_FRAME_SIZE: usize = 1 + 1 + 2 + u16::MAX as usize;
@@ -1,8 +1,27 @@
-use core::{convert::TryFrom, fmt::Debug};
+use alloc::{borrow::Cow, format, string::ToString};
+use core::{
+    convert::TryFrom,
+    fmt::{Debug, Display, Formatter},
+    str::FromStr,
+};
 
-use crate::{address::Address, error::Error, head::TcpResponseHeader};
+use bytes::{BufMut, BytesMut};
+#[cfg(feature = "std")]
+use futures_lite::AsyncReadExt;
 
-#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[cfg(feature = "std")]
+use crate::ser::{check_version, read_version, DecodeBuf, Step};
+use crate::{
+    address::Address,
+    error::{Error, ErrorKind, Result},
+    head::{
+        AuthenticationRequest, AuthenticationResponse, PasswordRequest, PasswordResponse,
+        TcpRequestHeader, TcpResponseHeader,
+    },
+    ser::{DecodeLimits, DecodeSlice, Encode, SliceReader},
+};
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Method {
     #[default]
     NONE = 0x00,
@@ -15,21 +34,61 @@ impl Method {
     pub const fn size_hint() -> usize {
         1
     }
+
+    /// Raw METHOD value on the wire
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) const ALL: [Method; 4] = [
+        Method::NONE,
+        Method::GSSAPI,
+        Method::PASSWORD,
+        Method::NotAcceptable,
+    ];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Method::NONE => "NO_AUTH",
+            Method::GSSAPI => "GSSAPI",
+            Method::PASSWORD => "USERNAME_PASSWORD",
+            Method::NotAcceptable => "NO_ACCEPTABLE",
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses the names written by `Display`, ignoring case
+impl FromStr for Method {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Method> {
+        Method::ALL
+            .into_iter()
+            .find(|method| method.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::new(Replies::GeneralFailure, format!("unknown method {s:?}")))
+    }
 }
 
 impl TryFrom<u8> for Method {
     type Error = Error;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
         let m = match value {
             0x00 => Method::NONE,
             0x01 => Method::GSSAPI,
             0x02 => Method::PASSWORD,
             0xff => Method::NotAcceptable,
             c => {
-                return Err(Error::new(
+                return Err(Error::with_kind(
+                    ErrorKind::UnsupportedMethod(c),
                     Replies::GeneralFailure,
-                    format_args!("unsupported method {:#x}", c),
+                    format!("unsupported method {:#x}", c),
                 ))
             }
         };
@@ -37,25 +96,168 @@ impl TryFrom<u8> for Method {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// A single byte without version, to be read with [`Decode::decode`](crate::ser::Decode::decode)
+/// rather than `read`, and written with [`Encode::encode`] rather than `as_bytes`
+impl DecodeSlice for Method {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let value = Method::try_from(r.u8()?)?;
+        Ok((value, r.consumed()))
+    }
+}
+
+impl Encode for Method {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.as_u8());
+    }
+}
+
+/// Set of methods, a bitmap indexed by the METHOD value
+///
+/// Duplicates collapse, and iteration yields the methods in ascending order of
+/// their value.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MethodSet {
+    bits: [u64; 4],
+}
+
+impl MethodSet {
+    pub const fn new() -> MethodSet {
+        MethodSet { bits: [0; 4] }
+    }
+
+    /// Adds `method`, returns whether it was not in the set yet
+    pub fn insert(&mut self, method: Method) -> bool {
+        let (word, bit) = Self::position(method);
+        let absent = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        absent
+    }
+
+    pub fn contains(&self, method: Method) -> bool {
+        let (word, bit) = Self::position(method);
+        self.bits[word] & bit != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == [0; 4]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Method> + '_ {
+        Method::ALL
+            .into_iter()
+            .filter(|method| self.contains(*method))
+    }
+
+    /// Methods in both sets
+    pub fn intersection(&self, other: &MethodSet) -> MethodSet {
+        let mut bits = self.bits;
+        for (word, other) in bits.iter_mut().zip(other.bits) {
+            *word &= other;
+        }
+        MethodSet { bits }
+    }
+
+    fn position(method: Method) -> (usize, u64) {
+        let value = method.as_u8() as usize;
+        (value / 64, 1 << (value % 64))
+    }
+}
+
+impl Debug for MethodSet {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<Method> for MethodSet {
+    fn from_iter<I: IntoIterator<Item = Method>>(iter: I) -> MethodSet {
+        let mut set = MethodSet::new();
+        for method in iter {
+            set.insert(method);
+        }
+        set
+    }
+}
+
+impl<'a> From<&'a [Method]> for MethodSet {
+    fn from(methods: &'a [Method]) -> MethodSet {
+        methods.iter().copied().collect()
+    }
+}
+
+impl<'a> From<&'a AuthenticationRequest> for MethodSet {
+    fn from(request: &'a AuthenticationRequest) -> MethodSet {
+        MethodSet::from(request.methods())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Command {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssociate = 0x03,
 }
 
+impl Command {
+    /// Raw CMD value on the wire
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) const ALL: [Command; 3] = [Command::Connect, Command::Bind, Command::UdpAssociate];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Command::Connect => "CONNECT",
+            Command::Bind => "BIND",
+            Command::UdpAssociate => "UDP_ASSOCIATE",
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses the names written by `Display`, ignoring case
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Command> {
+        Command::ALL
+            .into_iter()
+            .find(|command| command.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::new(Replies::GeneralFailure, format!("unknown command {s:?}")))
+    }
+}
+
 impl TryFrom<u8> for Command {
     type Error = Error;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
         let c = match value {
             0x01 => Command::Connect,
             0x02 => Command::Bind,
             0x03 => Command::UdpAssociate,
             c => {
-                return Err(Error::new(
-                    Replies::GeneralFailure,
-                    format_args!("unsupported command {:#x}", c),
+                return Err(Error::with_kind(
+                    ErrorKind::UnsupportedCommand(c),
+                    Replies::CommandNotSupported,
+                    format!("unsupported command {:#x}", c),
                 ))
             }
         };
@@ -63,30 +265,133 @@ impl TryFrom<u8> for Command {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+/// A single byte without version, see the impl for [`Method`]
+impl DecodeSlice for Command {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let value = Command::try_from(r.u8()?)?;
+        Ok((value, r.consumed()))
+    }
+}
+
+impl Encode for Command {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.as_u8());
+    }
+}
+
+/// Reply code of a response header
+///
+/// Failures are classified by [`is_retryable`](Replies::is_retryable),
+/// [`is_client_error`](Replies::is_client_error) and
+/// [`is_network_error`](Replies::is_network_error), as noted on each variant.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Replies {
-    Succeeded = 0x00,
-    GeneralFailure = 0x01,
-    ConnectionNotAllowed = 0x02,
-    NetworkUnreachable = 0x03,
-    HostUnreachable = 0x04,
-    ConnectionRefused = 0x05,
-    TtlExpired = 0x06,
-    CommandNotSupported = 0x07,
-    AddressTypeNotSupported = 0x08,
+    Succeeded,
+    /// Retryable
+    GeneralFailure,
+    /// Client error: the ruleset of the server forbids the request
+    ConnectionNotAllowed,
+    /// Retryable network error
+    NetworkUnreachable,
+    /// Retryable network error
+    HostUnreachable,
+    /// Network error, not retryable: nothing listens on the destination port
+    ConnectionRefused,
+    /// Retryable network error
+    TtlExpired,
+    /// Client error
+    CommandNotSupported,
+    /// Client error
+    AddressTypeNotSupported,
+    /// A code outside of the assigned range, as sent by some servers, kept as is;
+    /// unclassified since its meaning is unknown
+    ///
+    /// Holds `0x09..=0xff` only: [`Replies::from`] maps assigned codes to their
+    /// variant, and so do decoding and parsing. Built by hand with an assigned
+    /// code, it is encoded as that code but does not equal its variant.
+    Unassigned(u8),
 }
 
 impl Replies {
+    /// Raw REP value on the wire
+    ///
+    /// Replaces the `reply as u8` cast of versions before 0.5, which
+    /// [`Replies::Unassigned`] does not allow.
+    pub const fn as_u8(self) -> u8 {
+        match self {
+            Replies::Succeeded => 0x00,
+            Replies::GeneralFailure => 0x01,
+            Replies::ConnectionNotAllowed => 0x02,
+            Replies::NetworkUnreachable => 0x03,
+            Replies::HostUnreachable => 0x04,
+            Replies::ConnectionRefused => 0x05,
+            Replies::TtlExpired => 0x06,
+            Replies::CommandNotSupported => 0x07,
+            Replies::AddressTypeNotSupported => 0x08,
+            Replies::Unassigned(c) => c,
+        }
+    }
+
+    /// Whether the same request may succeed if tried again later
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Replies::GeneralFailure
+                | Replies::NetworkUnreachable
+                | Replies::HostUnreachable
+                | Replies::TtlExpired
+        )
+    }
+
+    /// Whether the request itself is at fault, so that retrying it is pointless
+    pub fn is_client_error(self) -> bool {
+        matches!(
+            self,
+            Replies::ConnectionNotAllowed
+                | Replies::CommandNotSupported
+                | Replies::AddressTypeNotSupported
+        )
+    }
+
+    /// Whether the server failed to reach the destination
+    pub fn is_network_error(self) -> bool {
+        matches!(
+            self,
+            Replies::NetworkUnreachable
+                | Replies::HostUnreachable
+                | Replies::ConnectionRefused
+                | Replies::TtlExpired
+        )
+    }
+
     pub fn into_response(self, address: Address) -> TcpResponseHeader {
         TcpResponseHeader::new(self, address)
     }
-}
 
-impl TryFrom<u8> for Replies {
-    type Error = Error;
+    /// HTTP status code conveying this reply, for HTTP-to-SOCKS gateways
+    pub fn http_status(self) -> u16 {
+        match self {
+            Replies::Succeeded => 200,
+            Replies::ConnectionNotAllowed => 403,
+            Replies::NetworkUnreachable | Replies::HostUnreachable => 502,
+            Replies::ConnectionRefused => 502,
+            Replies::TtlExpired => 504,
+            Replies::CommandNotSupported => 501,
+            Replies::AddressTypeNotSupported => 400,
+            Replies::GeneralFailure | Replies::Unassigned(_) => 502,
+        }
+    }
+}
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        let r = match value {
+/// Codes outside of the assigned range are kept as [`Replies::Unassigned`]
+impl From<u8> for Replies {
+    fn from(value: u8) -> Replies {
+        match value {
             0x00 => Replies::Succeeded,
             0x01 => Replies::GeneralFailure,
             0x02 => Replies::ConnectionNotAllowed,
@@ -96,32 +401,70 @@ impl TryFrom<u8> for Replies {
             0x06 => Replies::TtlExpired,
             0x07 => Replies::CommandNotSupported,
             0x08 => Replies::AddressTypeNotSupported,
-            c => {
-                return Err(Error::new(
-                    Replies::GeneralFailure,
-                    format_args!("unsupported reply {:#x}", c),
-                ))
-            }
-        };
-        Ok(r)
+            c => Replies::Unassigned(c),
+        }
     }
 }
 
+/// A single byte without version, see the impl for [`Method`]
+impl DecodeSlice for Replies {
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut r = SliceReader::new(buf);
+        let value = Replies::from(r.u8()?);
+        Ok((value, r.consumed()))
+    }
+}
+
+impl Encode for Replies {
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(self.as_u8());
+    }
+}
+
+/// Reply to a failed connection to the destination
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Replies {
     fn from(error: std::io::Error) -> Replies {
+        Replies::from(&error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<&std::io::Error> for Replies {
+    fn from(error: &std::io::Error) -> Replies {
         use std::io::ErrorKind;
 
         match error.kind() {
             ErrorKind::ConnectionRefused => Replies::ConnectionRefused,
             ErrorKind::ConnectionAborted => Replies::HostUnreachable,
-            _ => Replies::NetworkUnreachable,
+            ErrorKind::TimedOut => Replies::TtlExpired,
+            ErrorKind::PermissionDenied => Replies::ConnectionNotAllowed,
+            ErrorKind::AddrNotAvailable | ErrorKind::HostUnreachable => Replies::HostUnreachable,
+            ErrorKind::NetworkUnreachable => Replies::NetworkUnreachable,
+            _ => Replies::GeneralFailure,
         }
     }
 }
 
-impl From<Replies> for Error {
-    fn from(reply: Replies) -> Error {
-        let message = match reply {
+impl Replies {
+    pub(crate) const ALL: [Replies; 9] = [
+        Replies::Succeeded,
+        Replies::GeneralFailure,
+        Replies::ConnectionNotAllowed,
+        Replies::NetworkUnreachable,
+        Replies::HostUnreachable,
+        Replies::ConnectionRefused,
+        Replies::TtlExpired,
+        Replies::CommandNotSupported,
+        Replies::AddressTypeNotSupported,
+    ];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
             Replies::Succeeded => "succeeded",
             Replies::GeneralFailure => "general failure",
             Replies::ConnectionNotAllowed => "connection not allowed",
@@ -131,7 +474,415 @@ impl From<Replies> for Error {
             Replies::TtlExpired => "TTL expired",
             Replies::CommandNotSupported => "command not supported",
             Replies::AddressTypeNotSupported => "address type not supported",
+            Replies::Unassigned(_) => "unassigned reply",
+        }
+    }
+}
+
+/// Stable names such as `host unreachable`, for logs and configuration files;
+/// unassigned codes are written as `unassigned reply 0x09`
+impl Display for Replies {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            Replies::Unassigned(c) => write!(f, "{} {c:#04x}", self.as_str()),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+/// Parses the names written by `Display`, ignoring case
+impl FromStr for Replies {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Replies> {
+        const UNASSIGNED: &str = "unassigned reply 0x";
+        let unassigned = s
+            .get(..UNASSIGNED.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(UNASSIGNED))
+            .and_then(|_| u8::from_str_radix(&s[UNASSIGNED.len()..], 16).ok())
+            .map(Replies::from)
+            .filter(|reply| matches!(reply, Replies::Unassigned(_)));
+        Replies::ALL
+            .into_iter()
+            .find(|reply| reply.as_str().eq_ignore_ascii_case(s))
+            .or(unassigned)
+            .ok_or_else(|| Error::new(Replies::GeneralFailure, format!("unknown reply {s:?}")))
+    }
+}
+
+impl From<Replies> for Error {
+    fn from(reply: Replies) -> Error {
+        let message = match reply {
+            Replies::Unassigned(_) => Cow::Owned(reply.to_string()),
+            _ => Cow::Borrowed(reply.as_str()),
         };
-        Error::new(reply, message)
+        Error::with_kind(ErrorKind::Reply(reply), reply, message)
+    }
+}
+
+/// Any SOCKS5 handshake message
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Message {
+    AuthRequest(AuthenticationRequest),
+    AuthResponse(AuthenticationResponse),
+    PasswordRequest(PasswordRequest),
+    PasswordResponse(PasswordResponse),
+    Request(TcpRequestHeader),
+    Response(TcpResponseHeader),
+}
+
+/// Handshake phase, i.e. which message is expected next
+///
+/// Needed to decode a message since the wire format alone cannot tell
+/// some of them apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    AuthRequest,
+    AuthResponse,
+    PasswordRequest,
+    PasswordResponse,
+    Request,
+    Response,
+}
+
+impl Phase {
+    /// Version preceding messages of this phase
+    pub fn version(self) -> u8 {
+        match self {
+            Phase::PasswordRequest => <PasswordRequest as DecodeSlice>::VERSION,
+            Phase::PasswordResponse => <PasswordResponse as DecodeSlice>::VERSION,
+            _ => crate::VERSION,
+        }
+    }
+}
+
+impl Message {
+    /// Decodes the message expected in `phase`, without the version, see [`DecodeSlice`]
+    pub fn decode_slice(buf: &[u8], phase: Phase) -> Result<(Message, usize)> {
+        Message::decode_slice_limited(buf, phase, &DecodeLimits::default())
+    }
+
+    /// Decodes the message expected in `phase` like [`Message::decode_slice`],
+    /// see [`DecodeSlice::decode_slice_limited`]
+    pub fn decode_slice_limited(
+        buf: &[u8],
+        phase: Phase,
+        limits: &DecodeLimits,
+    ) -> Result<(Message, usize)> {
+        fn decode<D: DecodeSlice>(
+            buf: &[u8],
+            limits: &DecodeLimits,
+            f: fn(D) -> Message,
+        ) -> Result<(Message, usize)> {
+            D::decode_slice_limited(buf, limits).map(|(message, n)| (f(message), n))
+        }
+
+        match phase {
+            Phase::AuthRequest => decode(buf, limits, Message::AuthRequest),
+            Phase::AuthResponse => decode(buf, limits, Message::AuthResponse),
+            Phase::PasswordRequest => decode(buf, limits, Message::PasswordRequest),
+            Phase::PasswordResponse => decode(buf, limits, Message::PasswordResponse),
+            Phase::Request => decode(buf, limits, Message::Request),
+            Phase::Response => decode(buf, limits, Message::Response),
+        }
+    }
+
+    /// Checks the wrapped message against `limits`, see [`DecodeSlice::check_limits`]
+    pub fn check_limits(&self, limits: &DecodeLimits) -> Result<()> {
+        match self {
+            Message::AuthRequest(m) => m.check_limits(limits),
+            Message::AuthResponse(m) => m.check_limits(limits),
+            Message::PasswordRequest(m) => m.check_limits(limits),
+            Message::PasswordResponse(m) => m.check_limits(limits),
+            Message::Request(m) => m.check_limits(limits),
+            Message::Response(m) => m.check_limits(limits),
+        }
+    }
+
+    /// Reads the message expected in `phase`, without the version, like
+    /// [`Decode::decode`](crate::ser::Decode::decode)
+    #[cfg(feature = "std")]
+    pub async fn decode<T: AsyncReadExt + Unpin>(r: &mut T, phase: Phase) -> Result<Message> {
+        let mut buf = DecodeBuf::new();
+        loop {
+            let decode_slice = |buf: &[u8]| Message::decode_slice(buf, phase);
+            match buf.decode_with(decode_slice, crate::MAX_FRAME_SIZE)? {
+                Step::Done(message) => return Ok(message),
+                Step::Missing(missing) => r.read_exact(missing).await?,
+            }
+        }
+    }
+
+    /// Reads the message expected in `phase`, with the version, like
+    /// [`Decode::read`](crate::ser::Decode::read)
+    #[cfg(feature = "std")]
+    pub async fn read<T: AsyncReadExt + Unpin>(r: &mut T, phase: Phase) -> Result<Message> {
+        check_version(read_version(r).await?, phase.version())?;
+        Message::decode(r, phase).await
+    }
+
+    /// Phase in which this message is sent
+    pub fn phase(&self) -> Phase {
+        match self {
+            Message::AuthRequest(_) => Phase::AuthRequest,
+            Message::AuthResponse(_) => Phase::AuthResponse,
+            Message::PasswordRequest(_) => Phase::PasswordRequest,
+            Message::PasswordResponse(_) => Phase::PasswordResponse,
+            Message::Request(_) => Phase::Request,
+            Message::Response(_) => Phase::Response,
+        }
+    }
+}
+
+impl From<AuthenticationRequest> for Message {
+    fn from(message: AuthenticationRequest) -> Message {
+        Message::AuthRequest(message)
+    }
+}
+
+impl From<AuthenticationResponse> for Message {
+    fn from(message: AuthenticationResponse) -> Message {
+        Message::AuthResponse(message)
+    }
+}
+
+impl From<PasswordRequest> for Message {
+    fn from(message: PasswordRequest) -> Message {
+        Message::PasswordRequest(message)
+    }
+}
+
+impl From<PasswordResponse> for Message {
+    fn from(message: PasswordResponse) -> Message {
+        Message::PasswordResponse(message)
+    }
+}
+
+impl From<TcpRequestHeader> for Message {
+    fn from(message: TcpRequestHeader) -> Message {
+        Message::Request(message)
+    }
+}
+
+impl From<TcpResponseHeader> for Message {
+    fn from(message: TcpResponseHeader) -> Message {
+        Message::Response(message)
+    }
+}
+
+/// Delegates to the wrapped message, whose version is written instead of
+/// [`Encode::VERSION`]
+impl Encode for Message {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Message::AuthRequest(m) => m.encoded_len(),
+            Message::AuthResponse(m) => m.encoded_len(),
+            Message::PasswordRequest(m) => m.encoded_len(),
+            Message::PasswordResponse(m) => m.encoded_len(),
+            Message::Request(m) => m.encoded_len(),
+            Message::Response(m) => m.encoded_len(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Message::Request(m) => m.validate(),
+            Message::Response(m) => m.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        match self {
+            Message::AuthRequest(m) => m.encode_into(buffer),
+            Message::AuthResponse(m) => m.encode_into(buffer),
+            Message::PasswordRequest(m) => m.encode_into(buffer),
+            Message::PasswordResponse(m) => m.encode_into(buffer),
+            Message::Request(m) => m.encode_into(buffer),
+            Message::Response(m) => m.encode_into(buffer),
+        }
+    }
+
+    fn as_bytes_into(&self, buffer: &mut BytesMut) {
+        buffer.reserve(self.encoded_len() + 1);
+        buffer.put_u8(self.phase().version());
+        self.encode_into(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Every assigned reply, and unassigned codes at both ends of the range
+    fn all_replies() -> Vec<Replies> {
+        let mut replies = Replies::ALL.to_vec();
+        replies.extend([Replies::Unassigned(0x09), Replies::Unassigned(0xff)]);
+        replies
+    }
+
+    #[test]
+    fn replies_round_trip_through_their_names() {
+        for reply in all_replies() {
+            let name = reply.to_string();
+            assert_eq!(name.parse::<Replies>().unwrap(), reply);
+            assert_eq!(name.to_uppercase().parse::<Replies>().unwrap(), reply);
+            assert_eq!(Error::from(reply).message(), name);
+        }
+        assert_eq!(Replies::TtlExpired.to_string(), "TTL expired");
+        assert_eq!(
+            Replies::Unassigned(0x09).to_string(),
+            "unassigned reply 0x09"
+        );
+        let unknown = ["", "host", "unassigned reply 0x05", "0x04"];
+        for name in unknown {
+            assert!(name.parse::<Replies>().is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn assigned_codes_are_never_unassigned() {
+        for code in 0..=u8::MAX {
+            let reply = Replies::from(code);
+            assert_eq!(reply.as_u8(), code);
+            let unassigned = matches!(reply, Replies::Unassigned(_));
+            assert_eq!(unassigned, code > 0x08, "{code:#x}");
+            let (decoded, _) = Replies::decode_slice(&[code]).unwrap();
+            assert_eq!(decoded, reply);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn io_errors_map_to_replies() {
+        use std::io::{self, ErrorKind};
+
+        let table = [
+            (ErrorKind::ConnectionRefused, 0x05),
+            (ErrorKind::ConnectionAborted, 0x04),
+            (ErrorKind::TimedOut, 0x06),
+            (ErrorKind::PermissionDenied, 0x02),
+            (ErrorKind::AddrNotAvailable, 0x04),
+            (ErrorKind::HostUnreachable, 0x04),
+            (ErrorKind::NetworkUnreachable, 0x03),
+            (ErrorKind::NotFound, 0x01),
+            (ErrorKind::Other, 0x01),
+        ];
+        for (kind, rep) in table {
+            let error = io::Error::from(kind);
+            assert_eq!(Replies::from(&error).as_u8(), rep, "{kind:?}");
+            assert_eq!(Replies::from(error).as_u8(), rep, "{kind:?}");
+        }
+        // EHOSTUNREACH and ENETUNREACH, as returned by connect
+        #[cfg(target_os = "linux")]
+        for (code, reply) in [
+            (113, Replies::HostUnreachable),
+            (101, Replies::NetworkUnreachable),
+        ] {
+            assert_eq!(Replies::from(io::Error::from_raw_os_error(code)), reply);
+        }
+    }
+
+    #[test]
+    fn replies_are_classified() {
+        // reply, retryable, client error, network error
+        let table = [
+            (Replies::Succeeded, false, false, false),
+            (Replies::GeneralFailure, true, false, false),
+            (Replies::ConnectionNotAllowed, false, true, false),
+            (Replies::NetworkUnreachable, true, false, true),
+            (Replies::HostUnreachable, true, false, true),
+            (Replies::ConnectionRefused, false, false, true),
+            (Replies::TtlExpired, true, false, true),
+            (Replies::CommandNotSupported, false, true, false),
+            (Replies::AddressTypeNotSupported, false, true, false),
+            (Replies::Unassigned(0x09), false, false, false),
+            (Replies::Unassigned(0xff), false, false, false),
+        ];
+        assert_eq!(table.len(), all_replies().len());
+        for (reply, retryable, client_error, network_error) in table {
+            assert_eq!(reply.is_retryable(), retryable, "{reply}");
+            assert_eq!(reply.is_client_error(), client_error, "{reply}");
+            assert_eq!(reply.is_network_error(), network_error, "{reply}");
+        }
+    }
+
+    /// One message of every variant
+    fn all_messages() -> Vec<Message> {
+        let dest = Address::from_domain("example.com", 443).unwrap();
+        alloc::vec![
+            AuthenticationRequest::new([Method::NONE, Method::GSSAPI, Method::PASSWORD]).into(),
+            AuthenticationResponse::from(Method::NotAcceptable).into(),
+            PasswordRequest::new(b"user", b"secret").unwrap().into(),
+            PasswordResponse::failure().into(),
+            TcpRequestHeader::connect(dest).into(),
+            TcpResponseHeader::success(Address::unspecified_v6(1080)).into(),
+        ]
+    }
+
+    #[test]
+    fn messages_round_trip_in_their_phase() {
+        for message in &all_messages() {
+            // exhaustive, so a new variant fails to compile until covered here
+            let phase = match message {
+                Message::AuthRequest(_) => Phase::AuthRequest,
+                Message::AuthResponse(_) => Phase::AuthResponse,
+                Message::PasswordRequest(_) => Phase::PasswordRequest,
+                Message::PasswordResponse(_) => Phase::PasswordResponse,
+                Message::Request(_) => Phase::Request,
+                Message::Response(_) => Phase::Response,
+            };
+            assert_eq!(message.phase(), phase);
+            let bytes = message.as_bytes();
+            assert_eq!(bytes[0], phase.version());
+            let decoded = Message::decode_slice(&bytes[1..], phase).unwrap();
+            assert_eq!(decoded, (message.clone(), bytes.len() - 1));
+            #[cfg(feature = "std")]
+            {
+                let read = futures_lite::future::block_on(Message::read(&mut &bytes[..], phase));
+                assert_eq!(&read.unwrap(), message);
+            }
+        }
+    }
+
+    #[test]
+    fn method_set_collapses_duplicates() {
+        let offered = [
+            Method::PASSWORD,
+            Method::NONE,
+            Method::PASSWORD,
+            Method::NONE,
+        ];
+        let set = MethodSet::from(&offered[..]);
+        assert_eq!(set.len(), 2);
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [Method::NONE, Method::PASSWORD]
+        );
+        assert_eq!(
+            AuthenticationRequest::new(offered).methods(),
+            [Method::PASSWORD, Method::NONE]
+        );
+
+        // a decoded request keeps the duplicates as sent
+        let (request, _) =
+            AuthenticationRequest::decode_slice(&[4, 0x02, 0x02, 0xff, 0xff]).unwrap();
+        assert_eq!(request.methods().len(), 4);
+        let set = request.method_set();
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [Method::PASSWORD, Method::NotAcceptable]
+        );
+        assert!(request.required_authentication());
+
+        let mut preferences = MethodSet::new();
+        assert!(preferences.insert(Method::NONE));
+        assert!(preferences.insert(Method::NotAcceptable));
+        assert!(!preferences.insert(Method::NONE));
+        let common = set.intersection(&preferences);
+        assert_eq!(common.iter().collect::<Vec<_>>(), [Method::NotAcceptable]);
+        assert!(set.intersection(&MethodSet::new()).is_empty());
     }
 }
@@ -1,34 +1,340 @@
-use std::future::Future;
+use alloc::{boxed::Box, sync::Arc};
+#[cfg(feature = "std")]
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "timeout")]
+use std::time::Duration;
 
+#[cfg(feature = "std")]
+use bytes::Buf;
 use bytes::{BufMut, Bytes, BytesMut};
-use futures_lite::AsyncReadExt;
+#[cfg(feature = "std")]
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+#[cfg(any(feature = "std", feature = "embedded-io-async"))]
+use tinyvec::TinyVec;
 
 use crate::{
-    error::{Error, Result},
-    message::Replies,
+    address::DomainPolicy,
+    error::{Error, ErrorKind, Result},
+    message::{Message, Method, Phase, Replies},
     VERSION,
 };
 
+/// Implementors provide `encode_into`, and `encoded_len` unless encoding the
+/// message to measure it is fine
 pub trait Encode {
-    fn encode(&self) -> Bytes;
+    /// Version written before the message by `as_bytes`
+    const VERSION: u8 = VERSION;
+
+    /// Length of the encoded message, without the version, so `as_bytes` returns
+    /// one more byte
+    fn encoded_len(&self) -> usize {
+        self.encode().len()
+    }
+
+    /// Fails if the message has no valid encoding, checked by the `write_to`
+    /// methods and the codecs; the messages of this crate are checked when built
+    /// instead, so a domain name longer than 255 bytes cannot even be represented
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn encode(&self) -> Bytes {
+        // not sized by `encoded_len`, which may be measured through `encode`
+        let mut buffer = BytesMut::new();
+        self.encode_into(&mut buffer);
+        buffer.freeze()
+    }
+
+    /// Appends the encoded message, without the version, to `buffer`
+    fn encode_into(&self, buffer: &mut BytesMut);
 
     fn as_bytes(&self) -> Bytes {
-        let mut buffer = BytesMut::with_capacity(1);
-        buffer.put_u8(VERSION);
-        buffer.extend(self.encode());
+        let mut buffer = BytesMut::with_capacity(self.encoded_len() + 1);
+        self.as_bytes_into(&mut buffer);
         buffer.freeze()
     }
+
+    /// Appends the encoded message, with the version, to `buffer`
+    fn as_bytes_into(&self, buffer: &mut BytesMut) {
+        buffer.reserve(self.encoded_len() + 1);
+        buffer.put_u8(Self::VERSION);
+        self.encode_into(buffer);
+    }
+
+    /// Writes the message, with the version, to `w` and flushes it
+    ///
+    /// Allocates the encoded message, use
+    /// [`write_to_reusing`](Encode::write_to_reusing) to write several messages
+    /// through one buffer.
+    ///
+    /// Not cancellation safe: dropped midway, part of the message may have been
+    /// written.
+    #[cfg(feature = "std")]
+    fn write_to<W: AsyncWriteExt + Unpin>(&self, w: &mut W) -> impl Future<Output = Result<()>> {
+        async move { self.write_to_reusing(w, &mut BytesMut::new()).await }
+    }
+
+    /// Same as [`write_to`](Encode::write_to), encoding into `buf`, which is
+    /// cleared first; once `buf` grew to the largest message written, writing
+    /// does not allocate
+    #[cfg(feature = "std")]
+    fn write_to_reusing<W: AsyncWriteExt + Unpin>(
+        &self,
+        w: &mut W,
+        buf: &mut BytesMut,
+    ) -> impl Future<Output = Result<()>> {
+        async move {
+            self.validate()?;
+            buf.clear();
+            self.as_bytes_into(buf);
+            w.write_all(buf).await?;
+            w.flush().await?;
+            Ok(())
+        }
+    }
+
+    /// Same as [`write_to`](Encode::write_to), over a tokio writer
+    #[cfg(feature = "tokio")]
+    fn write_to_tokio<W: tokio::io::AsyncWrite + Unpin>(
+        &self,
+        w: &mut W,
+    ) -> impl Future<Output = Result<()>> {
+        async move { self.write_to(&mut crate::compat::TokioIo(w)).await }
+    }
+
+    /// Same as [`write_to`](Encode::write_to), over an `embedded-io-async` writer
+    #[cfg(feature = "embedded-io-async")]
+    fn write_to_embedded<W: embedded_io_async::Write>(
+        &self,
+        w: &mut W,
+    ) -> impl core::future::Future<Output = Result<()>> {
+        async move {
+            self.validate()?;
+            crate::embedded::write_all(w, &self.as_bytes()).await
+        }
+    }
+
+    /// Blocking counterpart of [`write_to`](Encode::write_to)
+    #[cfg(feature = "sync")]
+    fn write_to_sync<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        self.validate()?;
+        w.write_all(&self.as_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
 }
 
+/// Forwards to the message behind the pointer, so it can be written without moving it
+impl<T: Encode + ?Sized> Encode for &T {
+    const VERSION: u8 = T::VERSION;
+
+    fn encoded_len(&self) -> usize {
+        (**self).encoded_len()
+    }
+
+    fn validate(&self) -> Result<()> {
+        (**self).validate()
+    }
+
+    fn encode(&self) -> Bytes {
+        (**self).encode()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        (**self).encode_into(buffer)
+    }
+
+    fn as_bytes_into(&self, buffer: &mut BytesMut) {
+        (**self).as_bytes_into(buffer)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Box<T> {
+    const VERSION: u8 = T::VERSION;
+
+    fn encoded_len(&self) -> usize {
+        (**self).encoded_len()
+    }
+
+    fn validate(&self) -> Result<()> {
+        (**self).validate()
+    }
+
+    fn encode(&self) -> Bytes {
+        (**self).encode()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        (**self).encode_into(buffer)
+    }
+
+    fn as_bytes_into(&self, buffer: &mut BytesMut) {
+        (**self).as_bytes_into(buffer)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Arc<T> {
+    const VERSION: u8 = T::VERSION;
+
+    fn encoded_len(&self) -> usize {
+        (**self).encoded_len()
+    }
+
+    fn validate(&self) -> Result<()> {
+        (**self).validate()
+    }
+
+    fn encode(&self) -> Bytes {
+        (**self).encode()
+    }
+
+    fn encode_into(&self, buffer: &mut BytesMut) {
+        (**self).encode_into(buffer)
+    }
+
+    fn as_bytes_into(&self, buffer: &mut BytesMut) {
+        (**self).as_bytes_into(buffer)
+    }
+}
+
+/// Decoding from a buffer, without any I/O
+pub trait DecodeSlice: Sized {
+    /// Version expected before the message by [`Decode::read`]
+    const VERSION: u8 = VERSION;
+
+    /// Decodes a message, without the version, from the start of `buf`
+    ///
+    /// Returns the message and the number of bytes it occupies. If `buf` ends
+    /// before the message does, the error [is incomplete](Error::is_incomplete),
+    /// and the call can be retried with more bytes.
+    fn decode_slice(buf: &[u8]) -> Result<(Self, usize)>;
+
+    /// Decodes a message like [`decode_slice`](Self::decode_slice), following the
+    /// [`DomainPolicy`] of `limits`, and checks it against `limits`
+    ///
+    /// `decode_slice` is the same as this with the default limits.
+    fn decode_slice_limited(buf: &[u8], limits: &DecodeLimits) -> Result<(Self, usize)> {
+        let (message, n) = Self::decode_slice(buf)?;
+        message.check_limits(limits)?;
+        Ok((message, n))
+    }
+
+    /// Checks the decoded message against `limits`, failing with
+    /// [`ErrorKind::LimitExceeded`]
+    fn check_limits(&self, _limits: &DecodeLimits) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Ceilings on the sizes decoded from untrusted input, and the characters
+/// accepted in domain names
+///
+/// The defaults are the largest sizes the wire format allows, tighten them when
+/// exposed to hostile networks. See [`read_limited`] and
+/// [`Socks5Codec::set_limits`](crate::codec::Socks5Codec::set_limits).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Most methods offered by an authentication request
+    pub max_methods: usize,
+    /// Longest domain name of an address
+    pub max_domain_len: usize,
+    /// Longest username of a password request
+    pub max_username_len: usize,
+    /// Longest password of a password request
+    pub max_password_len: usize,
+    /// Longest frame the reading decoders buffer, version included; a message
+    /// declaring a longer length fails before it is read. Defaults to
+    /// [`MAX_FRAME_SIZE`](crate::MAX_FRAME_SIZE), lower it to refuse large
+    /// GSSAPI tokens
+    pub max_frame_len: usize,
+    /// Characters accepted in domain names
+    pub domain_policy: DomainPolicy,
+}
+
+impl DecodeLimits {
+    /// Fails with [`ErrorKind::LimitExceeded`] if `len` exceeds `limit`
+    pub(crate) fn check(what: &str, len: usize, limit: usize) -> Result<()> {
+        if len > limit {
+            return Err(Error::limit_exceeded(what, len, limit));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits {
+            max_methods: u8::MAX as usize,
+            max_domain_len: u8::MAX as usize,
+            max_username_len: u8::MAX as usize,
+            max_password_len: u8::MAX as usize,
+            max_frame_len: crate::MAX_FRAME_SIZE,
+            domain_policy: DomainPolicy::default(),
+        }
+    }
+}
+
+/// Cursor over a buffer, reporting missing bytes as [`ErrorKind::Incomplete`]
+pub(crate) struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> SliceReader<'a> {
+        SliceReader { buf, pos: 0 }
+    }
+
+    /// Number of bytes read so far
+    pub(crate) fn consumed(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let remaining = self.buf.len() - self.pos;
+        if remaining < n {
+            return Err(Error::incomplete(n - remaining));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn decode_limited<D: DecodeSlice>(&mut self, limits: &DecodeLimits) -> Result<D> {
+        let (message, n) = D::decode_slice_limited(&self.buf[self.pos..], limits)?;
+        self.pos += n;
+        Ok(message)
+    }
+}
+
+/// Reading a message from an async stream
+///
+/// The read futures are not cancellation safe: dropped midway, such as by a
+/// `select!` or a timeout, the bytes read so far are lost and the stream is left
+/// within a message. Use a [`StreamDecoder`] to resume reading instead.
+#[cfg(feature = "std")]
 pub trait Decode<T: AsyncReadExt + Unpin>
 where
     Self: Sized,
 {
+    /// Version expected before the message by `read`
+    const VERSION: u8 = VERSION;
+
     fn decode(r: &mut T) -> impl Future<Output = Result<Self>>;
 
     fn read_u8(r: &mut T) -> impl Future<Output = Result<u8>> {
         async {
-            let mut buf = vec![0; 1];
+            let mut buf = [0; 1];
             r.read_exact(&mut buf).await?;
             Ok(buf[0])
         }
@@ -36,15 +342,1115 @@ where
 
     fn read(r: &mut T) -> impl Future<Output = Result<Self>> {
         async {
-            let version = Self::read_u8(r).await?;
-            if version != VERSION {
-                return Err(Error::new(
-                    Replies::ConnectionRefused,
-                    format!("unsupported socks version {version:#x}"),
-                ));
+            check_version(read_version(r).await?, Self::VERSION)?;
+            Self::decode(r).await
+        }
+    }
+
+    /// Same as `read`, but fails with [`Error::timeout`] if the message is not
+    /// complete within `timeout`
+    #[cfg(feature = "timeout")]
+    fn read_timeout(r: &mut T, timeout: Duration) -> impl Future<Output = Result<Self>> {
+        async move {
+            let expired = async {
+                async_io::Timer::after(timeout).await;
+                Err(Error::timeout())
+            };
+            futures_lite::future::or(Self::read(r), expired).await
+        }
+    }
+}
+
+/// Reads the version of a message, a stream ending before it is reported as
+/// [`ErrorKind::Disconnected`]
+#[cfg(feature = "std")]
+pub(crate) async fn read_version<T: AsyncReadExt + Unpin>(r: &mut T) -> Result<u8> {
+    let mut buf = [0; 1];
+    if r.read(&mut buf).await? == 0 {
+        return Err(Error::disconnected());
+    }
+    Ok(buf[0])
+}
+
+pub(crate) fn check_version(version: u8, expected: u8) -> Result<()> {
+    if version != expected {
+        return Err(Error::wrong_version(version));
+    }
+    Ok(())
+}
+
+/// Size of the chunks read by [`ReadAhead`], large enough for any handshake message
+#[cfg(feature = "std")]
+const READ_AHEAD_SIZE: usize = 512;
+
+/// Reader used by [`read_buffered`], reads from the inner reader in chunks
+#[cfg(feature = "std")]
+pub struct ReadAhead<'a, T> {
+    inner: &'a mut T,
+    buf: BytesMut,
+}
+
+#[cfg(feature = "std")]
+impl<T: AsyncRead + Unpin> AsyncRead for ReadAhead<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.buf.is_empty() {
+            this.buf.resize(READ_AHEAD_SIZE, 0);
+            match Pin::new(&mut *this.inner).poll_read(cx, &mut this.buf) {
+                Poll::Ready(Ok(n)) => this.buf.truncate(n),
+                poll => {
+                    this.buf.clear();
+                    return poll;
+                }
             }
+        }
+        let n = out.len().min(this.buf.len());
+        out[..n].copy_from_slice(&this.buf[..n]);
+        this.buf.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
 
-            Self::decode(r).await
+/// Decoder keeping the bytes of a partially read message between calls
+///
+/// Unlike [`Decode::read`], [`read`](StreamDecoder::read) is cancellation safe:
+/// if its future is dropped before completion, the bytes read so far are kept and
+/// the next call resumes the message where it left off. It still never reads
+/// past the message, so the stream can be handed over to a relay afterwards.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    /// Version and start of the message being read
+    buf: BytesMut,
+    limits: DecodeLimits,
+}
+
+#[cfg(feature = "std")]
+impl StreamDecoder {
+    pub fn new() -> StreamDecoder {
+        StreamDecoder::default()
+    }
+
+    /// Decodes with [`DecodeSlice::decode_slice_limited`], frames longer than
+    /// `limits.max_frame_len` are refused before they are buffered
+    pub fn with_limits(limits: DecodeLimits) -> StreamDecoder {
+        StreamDecoder {
+            buf: BytesMut::new(),
+            limits,
+        }
+    }
+
+    /// Whether part of a message has been read, to be completed by the next call
+    pub fn is_partial(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Reads a message, with the version, like [`Decode::read`]
+    ///
+    /// Keep reading the same type of message until it is complete: the buffered
+    /// bytes belong to it. They are discarded on a decoding error.
+    pub async fn read<D, T>(&mut self, r: &mut T) -> Result<D>
+    where
+        D: DecodeSlice,
+        T: AsyncReadExt + Unpin,
+    {
+        loop {
+            let needed = match self.decode() {
+                Ok(Ok(message)) => return Ok(message),
+                Ok(Err(needed)) => needed,
+                Err(e) => {
+                    self.buf.clear();
+                    return Err(e);
+                }
+            };
+            let mut chunk = [0; READ_AHEAD_SIZE];
+            let len = needed.min(chunk.len());
+            // a read future dropped before completion consumed nothing
+            let n = r.read(&mut chunk[..len]).await?;
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Err(Error::disconnected());
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Decodes the buffered message, or tells how many more bytes are needed
+    fn decode<D: DecodeSlice>(&mut self) -> Result<core::result::Result<D, usize>> {
+        let (version, rest) = match self.buf.split_first() {
+            Some(v) => v,
+            None => return Ok(Err(1)),
+        };
+        check_version(*version, D::VERSION)?;
+        match D::decode_slice_limited(rest, &self.limits) {
+            Ok((message, _)) => {
+                self.buf.clear();
+                Ok(Ok(message))
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::Incomplete { needed } => {
+                    check_frame_len(self.buf.len() + needed, self.limits.max_frame_len)?;
+                    Ok(Err(needed.max(1)))
+                }
+                _ => Err(e),
+            },
         }
     }
 }
+
+/// Reads a message like [`Decode::read`], but reads ahead from `r` in chunks
+///
+/// Besides the message, returns the bytes read past it, such as application data
+/// pipelined by an optimistic client. The caller is responsible for forwarding them.
+#[cfg(feature = "std")]
+pub async fn read_buffered<'a, D, T>(r: &'a mut T) -> Result<(D, BytesMut)>
+where
+    D: Decode<ReadAhead<'a, T>>,
+    T: AsyncReadExt + Unpin,
+{
+    let mut reader = ReadAhead {
+        inner: r,
+        buf: BytesMut::new(),
+    };
+    let message = D::read(&mut reader).await?;
+    Ok((message, reader.buf))
+}
+
+/// Reads a message like [`read_counted`], decoding it with
+/// [`DecodeSlice::decode_slice_limited`]
+#[cfg(feature = "std")]
+pub async fn read_limited<D, T>(r: &mut T, limits: &DecodeLimits) -> Result<(D, usize)>
+where
+    D: DecodeSlice,
+    T: AsyncReadExt + Unpin,
+{
+    check_version(read_version(r).await?, D::VERSION)?;
+    let mut buf = DecodeBuf::new();
+    loop {
+        let decode_slice = |buf: &[u8]| D::decode_slice_limited(buf, limits);
+        match buf.decode_with(decode_slice, limits.max_frame_len)? {
+            Step::Done(message) => return Ok((message, buf.len() + 1)),
+            Step::Missing(missing) => r.read_exact(missing).await?,
+        }
+    }
+}
+
+/// Reads a message like [`Decode::read`], also returns the number of bytes it
+/// occupied on the wire, including the version
+#[cfg(feature = "std")]
+pub async fn read_counted<D, T>(r: &mut T) -> Result<(D, usize)>
+where
+    D: DecodeSlice,
+    T: AsyncReadExt + Unpin,
+{
+    read_limited(r, &DecodeLimits::default()).await
+}
+
+#[cfg(any(feature = "std", feature = "embedded-io-async"))]
+/// Outcome of [`DecodeBuf::decode`]
+pub(crate) enum Step<'a, D> {
+    /// The message, which occupies the whole buffer
+    Done(D),
+    /// Space to read the missing bytes into, before decoding again
+    Missing(&'a mut [u8]),
+}
+
+#[cfg(any(feature = "std", feature = "embedded-io-async"))]
+/// Buffer of the reading decode loops, which read exactly as many bytes as the
+/// message occupies, by asking [`DecodeSlice::decode_slice`] how many more are
+/// needed until it succeeds
+pub(crate) struct DecodeBuf {
+    // inline for the RFC 1928 and RFC 1929 messages, at most 512 bytes past the
+    // version; GSSAPI tokens of up to 65535 bytes spill to the heap
+    buf: TinyVec<[u8; 512]>,
+}
+
+#[cfg(any(feature = "std", feature = "embedded-io-async"))]
+impl DecodeBuf {
+    pub(crate) fn new() -> DecodeBuf {
+        DecodeBuf {
+            buf: TinyVec::new(),
+        }
+    }
+
+    /// Number of bytes read so far
+    #[cfg(feature = "std")]
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn decode<D: DecodeSlice>(&mut self) -> Result<Step<'_, D>> {
+        self.decode_with(D::decode_slice, crate::MAX_FRAME_SIZE)
+    }
+
+    /// Same as `decode`, with a decoding function such as a phase-aware one,
+    /// failing once the frame would exceed `max_frame_len` bytes
+    pub(crate) fn decode_with<D>(
+        &mut self,
+        decode_slice: impl FnOnce(&[u8]) -> Result<(D, usize)>,
+        max_frame_len: usize,
+    ) -> Result<Step<'_, D>> {
+        match decode_slice(&self.buf) {
+            Ok((message, _)) => Ok(Step::Done(message)),
+            Err(e) => match e.kind() {
+                ErrorKind::Incomplete { needed } => {
+                    // the version precedes the buffered bytes
+                    check_frame_len(1 + self.buf.len() + needed, max_frame_len)?;
+                    // always make progress, even if a decoder asks for nothing
+                    let len = self.buf.len();
+                    self.buf.resize(len + needed.max(1), 0);
+                    Ok(Step::Missing(&mut self.buf[len..]))
+                }
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+/// Fails once a frame of `len` bytes, version included, exceeds `max_frame_len`
+#[cfg(any(feature = "std", feature = "embedded-io-async"))]
+fn check_frame_len(len: usize, max_frame_len: usize) -> Result<()> {
+    DecodeLimits::check("frame length", len, max_frame_len)
+}
+
+#[cfg(feature = "std")]
+async fn decode_counted<D, T>(r: &mut T) -> Result<(D, usize)>
+where
+    D: DecodeSlice,
+    T: AsyncReadExt + Unpin,
+{
+    let mut buf = DecodeBuf::new();
+    loop {
+        match buf.decode()? {
+            Step::Done(message) => return Ok((message, buf.len())),
+            Step::Missing(missing) => r.read_exact(missing).await?,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: AsyncReadExt + Unpin, D: DecodeSlice> Decode<T> for D {
+    const VERSION: u8 = D::VERSION;
+
+    async fn decode(r: &mut T) -> Result<Self> {
+        let (message, _) = decode_counted(r).await?;
+        Ok(message)
+    }
+}
+
+/// Blocking counterpart of [`Decode`], for use without an executor
+#[cfg(feature = "sync")]
+pub trait DecodeSync<R: io::Read>: Sized {
+    /// Version expected before the message by `read`
+    const VERSION: u8 = VERSION;
+
+    fn decode(r: &mut R) -> Result<Self>;
+
+    fn read(r: &mut R) -> Result<Self> {
+        let mut buf = [0; 1];
+        if r.read(&mut buf)? == 0 {
+            return Err(Error::disconnected());
+        }
+        check_version(buf[0], Self::VERSION)?;
+        Self::decode(r)
+    }
+}
+
+/// Reads exactly as many bytes as the message occupies, like the [`Decode`] impl
+#[cfg(feature = "sync")]
+impl<R: io::Read, D: DecodeSlice> DecodeSync<R> for D {
+    const VERSION: u8 = D::VERSION;
+
+    fn decode(r: &mut R) -> Result<Self> {
+        let mut buf = DecodeBuf::new();
+        loop {
+            match buf.decode()? {
+                Step::Done(message) => return Ok(message),
+                Step::Missing(missing) => r.read_exact(missing)?,
+            }
+        }
+    }
+}
+
+/// Outcome of feeding bytes to a [`HandshakeParser`]
+pub enum Progress {
+    /// A message was parsed from the first `usize` bytes, including the version
+    Parsed(Message, usize),
+    /// At least this many more bytes are needed, nothing was consumed
+    NeedMoreData(usize),
+}
+
+/// Push-based parser for one side of a handshake, without any I/O
+///
+/// The caller owns the buffer: it feeds the bytes received so far, drops the
+/// consumed ones once a message is parsed, and feeds again. Each parsed message
+/// advances the phase; after the request (or response) header the handshake is
+/// over and [`phase`](HandshakeParser::phase) returns `None`.
+pub struct HandshakeParser {
+    phase: Option<Phase>,
+}
+
+impl HandshakeParser {
+    /// Parses the messages sent by a client, for use in a server
+    pub fn server() -> HandshakeParser {
+        HandshakeParser {
+            phase: Some(Phase::AuthRequest),
+        }
+    }
+
+    /// Parses the messages sent by a server, for use in a client
+    pub fn client() -> HandshakeParser {
+        HandshakeParser {
+            phase: Some(Phase::AuthResponse),
+        }
+    }
+
+    /// Message expected next, `None` once the handshake is over
+    pub fn phase(&self) -> Option<Phase> {
+        self.phase
+    }
+
+    /// Tells a server-side parser which method was selected in reply to the
+    /// authentication request, so that a password request is expected if needed
+    pub fn select_method(&mut self, method: Method) {
+        if self.phase == Some(Phase::Request) && method == Method::PASSWORD {
+            self.phase = Some(Phase::PasswordRequest);
+        }
+    }
+
+    pub fn feed(&mut self, buf: &[u8]) -> Result<Progress> {
+        let phase = self
+            .phase
+            .ok_or_else(|| Error::new(Replies::GeneralFailure, "handshake is already finished"))?;
+        let version = match buf.first() {
+            Some(version) => *version,
+            None => return Ok(Progress::NeedMoreData(1)),
+        };
+        check_version(version, phase.version())?;
+        let (message, n) = match Message::decode_slice(&buf[1..], phase) {
+            Ok(v) => v,
+            Err(e) => match e.kind() {
+                ErrorKind::Incomplete { needed } => return Ok(Progress::NeedMoreData(needed)),
+                _ => return Err(e),
+            },
+        };
+        self.phase = match &message {
+            Message::AuthRequest(_) => Some(Phase::Request),
+            Message::AuthResponse(resp) if resp.method() == Method::PASSWORD => {
+                Some(Phase::PasswordResponse)
+            }
+            Message::AuthResponse(_) => Some(Phase::Response),
+            Message::PasswordRequest(_) => Some(Phase::Request),
+            Message::PasswordResponse(_) => Some(Phase::Response),
+            Message::Request(_) | Message::Response(_) => None,
+        };
+        Ok(Progress::Parsed(message, n + 1))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use alloc::vec::Vec;
+    use core::{
+        cell::Cell,
+        fmt::Debug,
+        net::{SocketAddrV4, SocketAddrV6},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        io,
+    };
+
+    use bytes::BytesMut;
+    use fastrand::Rng;
+    use futures_lite::{future::block_on, io::sink, AsyncWrite};
+
+    use super::*;
+    use crate::{
+        address::Address,
+        head::{
+            AuthenticationRequest, AuthenticationResponse, GssapiMessage, GssapiMessageType,
+            PasswordRequest, PasswordResponse, TcpRequestHeader, TcpResponseHeader,
+        },
+        message::Command,
+        MAX_FRAME_SIZE,
+    };
+
+    /// Counts the allocations of the current thread, and the bytes they asked for
+    struct Counting;
+
+    std::thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    fn allocated() -> usize {
+        ALLOCATED.with(Cell::get)
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    struct Failing;
+
+    impl AsyncWrite for Failing {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn request() -> TcpRequestHeader {
+        TcpRequestHeader::connect(Address::from_domain("example.com", 443).unwrap())
+    }
+
+    #[test]
+    fn write_to_reusing_does_not_allocate_once_warm() {
+        let request = request();
+        let mut buf = BytesMut::new();
+        block_on(async {
+            request
+                .write_to_reusing(&mut sink(), &mut buf)
+                .await
+                .unwrap();
+            let before = allocations();
+            for _ in 0..16 {
+                request
+                    .write_to_reusing(&mut sink(), &mut buf)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(allocations(), before);
+            request.write_to(&mut sink()).await.unwrap();
+            assert!(allocations() > before);
+        });
+        assert_eq!(buf[..], request.as_bytes()[..]);
+    }
+
+    #[test]
+    fn write_error_is_returned() {
+        let err = block_on(request().write_to(&mut Failing)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Io);
+        let err = block_on(request().write_to_reusing(&mut Failing, &mut BytesMut::new()));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::Io);
+    }
+
+    /// Allocated by decoding besides the bytes of the input: the boxes of the
+    /// messages and the error
+    const OVERHEAD: usize = 1024;
+
+    /// Feeds `input`, without the version, to the slice decoder and the reading
+    /// ones, all of which must fail without allocating past the declared lengths
+    fn rejects<D: DecodeSlice + Debug>(input: &[u8]) {
+        let before = allocated();
+        let decoded = D::decode_slice(input);
+        assert!(decoded.is_err(), "{input:x?} decoded as {decoded:?}");
+        assert!(D::decode_slice_limited(input, &DecodeLimits::default()).is_err());
+        assert!(allocated() - before <= input.len() + OVERHEAD);
+
+        let mut framed = alloc::vec![<D as DecodeSlice>::VERSION];
+        framed.extend_from_slice(input);
+        let before = allocated();
+        block_on(async {
+            assert!(<D as Decode<&[u8]>>::read(&mut &framed[..]).await.is_err());
+            let limits = DecodeLimits::default();
+            assert!(read_limited::<D, _>(&mut &framed[..], &limits)
+                .await
+                .is_err());
+            let mut decoder = StreamDecoder::new();
+            assert!(decoder.read::<D, _>(&mut &framed[..]).await.is_err());
+        });
+        #[cfg(feature = "sync")]
+        assert!(<D as DecodeSync<&[u8]>>::read(&mut &framed[..]).is_err());
+        // a declared length is read into one buffer, whatever the input holds
+        assert!(allocated() - before <= 3 * (2 * MAX_FRAME_SIZE + OVERHEAD));
+    }
+
+    #[test]
+    fn rejects_malformed_auth_requests() {
+        rejects::<AuthenticationRequest>(&[]);
+        // no method
+        rejects::<AuthenticationRequest>(&[0]);
+        // NMETHODS larger than the methods sent
+        rejects::<AuthenticationRequest>(&[255]);
+        rejects::<AuthenticationRequest>(&[255, 0, 2]);
+        // unsupported method
+        rejects::<AuthenticationRequest>(&[2, 0, 0x80]);
+        rejects::<AuthenticationResponse>(&[]);
+        rejects::<AuthenticationResponse>(&[0x03]);
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        rejects::<Address>(&[]);
+        // unknown ATYP
+        rejects::<Address>(&[0x02, 127, 0, 0, 1, 0, 80]);
+        rejects::<Address>(&[0x01, 127, 0, 0]);
+        // truncated IPv6 bodies
+        rejects::<Address>(&[0x04]);
+        rejects::<Address>(&[0x04, 0xfe, 0x80, 0, 0, 0, 0, 0, 0]);
+        rejects::<Address>(&[&[0x04][..], &[0; 17]].concat());
+        // domain_len 0
+        rejects::<Address>(&[0x03, 0, 0, 80]);
+        // domain_len 255 with fewer bytes
+        rejects::<Address>(&[0x03, 255, b'a', b'b', 0, 80]);
+        rejects::<Address>(&[&[0x03, 255][..], &[b'a'; 255]].concat());
+        // domain_len 255 with a NUL
+        let mut name = [b'a'; 255];
+        name[254] = 0;
+        rejects::<Address>(&[&[0x03, 255][..], &name, &[0, 80]].concat());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let target = [0x01, 127, 0, 0, 1, 0, 80];
+        rejects::<TcpRequestHeader>(&[]);
+        rejects::<TcpRequestHeader>(&[0x01]);
+        // garbage RSV
+        rejects::<TcpRequestHeader>(&[&[0x01, 0xff][..], &target].concat());
+        rejects::<TcpResponseHeader>(&[&[0x00, 0x42][..], &target].concat());
+        // unsupported CMD
+        rejects::<TcpRequestHeader>(&[&[0x09, 0][..], &target].concat());
+        rejects::<TcpRequestHeader>(&[0x01, 0, 0x04, 0xfe, 0x80]);
+        rejects::<TcpRequestHeader>(&[0x01, 0, 0x03, 0, 0, 80]);
+        rejects::<TcpResponseHeader>(&[0x00, 0, 0x04, 0, 0]);
+        rejects::<TcpResponseHeader>(&[0x00, 0, 0x05]);
+    }
+
+    #[test]
+    fn rejects_malformed_subnegotiations() {
+        rejects::<PasswordRequest>(&[]);
+        rejects::<PasswordRequest>(&[5, b'a', b'b']);
+        rejects::<PasswordRequest>(&[255]);
+        rejects::<PasswordRequest>(&[1, b'a', 255, b'x']);
+        rejects::<PasswordResponse>(&[]);
+        rejects::<GssapiMessage>(&[]);
+        rejects::<GssapiMessage>(&[0x04]);
+        rejects::<GssapiMessage>(&[0x01, 0xff]);
+        // a token of 65535 bytes declared, 3 sent
+        rejects::<GssapiMessage>(&[0x01, 0xff, 0xff, 1, 2, 3]);
+        rejects::<Method>(&[]);
+        rejects::<Method>(&[0x80]);
+        rejects::<Command>(&[0x00]);
+        rejects::<Command>(&[0x04]);
+        rejects::<Replies>(&[]);
+    }
+
+    fn random_address(rng: &mut Rng) -> Address {
+        match rng.u8(0..3) {
+            0 => SocketAddrV4::new(rng.u32(..).into(), rng.u16(..)).into(),
+            1 => SocketAddrV6::new(rng.u128(..).into(), rng.u16(..), 0, 0).into(),
+            _ => {
+                // printable characters, which any policy but the strict one accepts
+                let name: Vec<u8> = (0..rng.usize(1..=255))
+                    .map(|_| rng.u8(0x20..0x7f))
+                    .collect();
+                Address::try_from((&name[..], rng.u16(..))).unwrap()
+            }
+        }
+    }
+
+    fn random_method(rng: &mut Rng) -> Method {
+        Method::ALL[rng.usize(..Method::ALL.len())]
+    }
+
+    fn random_bytes(rng: &mut Rng, max: usize) -> Vec<u8> {
+        (0..rng.usize(0..=max)).map(|_| rng.u8(..)).collect()
+    }
+
+    /// Valid messages of every type, encoded with the version, along with the
+    /// phase of the handshake they belong to, `None` for GSSAPI messages
+    fn random_messages(rng: &mut Rng) -> Vec<(Option<Phase>, Bytes)> {
+        let methods: Vec<Method> = (0..rng.usize(1..8)).map(|_| random_method(rng)).collect();
+        let command = [Command::Connect, Command::Bind, Command::UdpAssociate][rng.usize(..3)];
+        let password = PasswordRequest::new(&random_bytes(rng, 255), &random_bytes(rng, 255));
+        let gssapi_type = [
+            GssapiMessageType::Authentication,
+            GssapiMessageType::Protection,
+            GssapiMessageType::Encapsulation,
+        ][rng.usize(..3)];
+        let gssapi = match rng.bool() {
+            true => GssapiMessage::new(gssapi_type, random_bytes(rng, 1024)).unwrap(),
+            false => GssapiMessage::abort(),
+        };
+        alloc::vec![
+            (
+                Some(Phase::AuthRequest),
+                AuthenticationRequest::new(methods).as_bytes()
+            ),
+            (
+                Some(Phase::AuthResponse),
+                AuthenticationResponse::from(random_method(rng)).as_bytes()
+            ),
+            (Some(Phase::PasswordRequest), password.unwrap().as_bytes()),
+            (
+                Some(Phase::PasswordResponse),
+                Bytes::from(alloc::vec![1, rng.u8(..)])
+            ),
+            (
+                Some(Phase::Request),
+                TcpRequestHeader::new(command, random_address(rng)).as_bytes()
+            ),
+            (
+                Some(Phase::Response),
+                TcpResponseHeader::new(Replies::from(rng.u8(..)), random_address(rng)).as_bytes()
+            ),
+            (None, gssapi.as_bytes()),
+        ]
+    }
+
+    /// Decodes `input` as a `D`, if it does decode the message must encode back
+    /// to the consumed bytes
+    fn decodes_canonically<D: DecodeSlice + Encode + PartialEq + Debug>(input: &[u8]) -> bool {
+        match D::decode_slice(input) {
+            Ok((message, n)) => {
+                assert_eq!(message.encode()[..], input[..n], "{message:?}");
+                assert_eq!(message.encoded_len(), n);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn decodes_canonically_as_any(input: &[u8]) {
+        decodes_canonically::<AuthenticationRequest>(input);
+        decodes_canonically::<AuthenticationResponse>(input);
+        decodes_canonically::<PasswordRequest>(input);
+        decodes_canonically::<PasswordResponse>(input);
+        decodes_canonically::<TcpRequestHeader>(input);
+        decodes_canonically::<TcpResponseHeader>(input);
+        decodes_canonically::<GssapiMessage>(input);
+        decodes_canonically::<Address>(input);
+        decodes_canonically::<Method>(input);
+        decodes_canonically::<Command>(input);
+        decodes_canonically::<Replies>(input);
+        for phase in [
+            Phase::AuthRequest,
+            Phase::AuthResponse,
+            Phase::PasswordRequest,
+            Phase::PasswordResponse,
+            Phase::Request,
+            Phase::Response,
+        ] {
+            let _ = Message::decode_slice(input, phase);
+        }
+    }
+
+    #[test]
+    fn random_messages_round_trip() {
+        let mut rng = Rng::with_seed(0x50c5);
+        for _ in 0..500 {
+            for (phase, bytes) in random_messages(&mut rng) {
+                let (version, body) = bytes.split_first().unwrap();
+                let (encoded, n) = match phase {
+                    Some(phase) => {
+                        assert_eq!(*version, phase.version());
+                        let (message, n) = Message::decode_slice(body, phase).unwrap();
+                        (message.encode(), n)
+                    }
+                    None => {
+                        let (message, n) = GssapiMessage::decode_slice(body).unwrap();
+                        (message.encode(), n)
+                    }
+                };
+                assert_eq!(n, body.len());
+                assert_eq!(encoded[..], body[..]);
+            }
+        }
+    }
+
+    fn assert_encoded_len<M: Encode + Debug>(message: &M) {
+        assert_eq!(message.encoded_len(), message.encode().len(), "{message:?}");
+        assert_eq!(message.as_bytes().len(), message.encoded_len() + 1);
+    }
+
+    #[test]
+    fn encoded_len_matches_the_encoding() {
+        let mut rng = Rng::with_seed(0x0062);
+        for _ in 0..500 {
+            assert_encoded_len(&random_address(&mut rng));
+            for (phase, bytes) in random_messages(&mut rng) {
+                match phase {
+                    Some(phase) => {
+                        let (message, _) = Message::decode_slice(&bytes[1..], phase).unwrap();
+                        assert_encoded_len(&message);
+                        match message {
+                            Message::AuthRequest(m) => assert_encoded_len(&m),
+                            Message::AuthResponse(m) => assert_encoded_len(&m),
+                            Message::PasswordRequest(m) => assert_encoded_len(&m),
+                            Message::PasswordResponse(m) => assert_encoded_len(&m),
+                            Message::Request(m) => assert_encoded_len(&m),
+                            Message::Response(m) => assert_encoded_len(&m),
+                        }
+                    }
+                    None => {
+                        let (message, _) = GssapiMessage::decode_slice(&bytes[1..]).unwrap();
+                        assert_encoded_len(&message);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutated_messages_never_panic() {
+        let mut rng = Rng::with_seed(0x1928);
+        for _ in 0..500 {
+            for (_, bytes) in random_messages(&mut rng) {
+                let mut body = bytes[1..].to_vec();
+                if !body.is_empty() {
+                    let i = rng.usize(..body.len());
+                    body[i] = rng.u8(..);
+                    body.truncate(rng.usize(..=body.len()));
+                }
+                decodes_canonically_as_any(&body);
+            }
+            decodes_canonically_as_any(&random_bytes(&mut rng, 64));
+        }
+    }
+
+    /// Checks that every strict prefix of `body` is incomplete, and that reading
+    /// as many more bytes as asked for ends at the end of the message
+    fn decodes_once_complete<D: DecodeSlice + Debug>(body: &[u8]) {
+        for len in 0..body.len() {
+            match D::decode_slice(&body[..len]).unwrap_err().kind() {
+                ErrorKind::Incomplete { needed } => assert!(needed >= 1),
+                kind => panic!("{body:x?} cut at {len}: {kind:?}"),
+            }
+        }
+        let mut len = 0;
+        let n = loop {
+            match D::decode_slice(&body[..len]) {
+                Ok((_, n)) => break n,
+                Err(e) => match e.kind() {
+                    ErrorKind::Incomplete { needed } => len += needed,
+                    kind => panic!("{kind:?}"),
+                },
+            }
+        };
+        assert_eq!((n, len), (body.len(), body.len()));
+        let padded = [body, b"trailing"].concat();
+        assert_eq!(D::decode_slice(&padded).unwrap().1, body.len());
+    }
+
+    #[test]
+    fn truncated_messages_are_incomplete() {
+        decodes_once_complete::<AuthenticationRequest>(&[3, 0x00, 0x01, 0x02]);
+        decodes_once_complete::<AuthenticationResponse>(&[0x02]);
+        decodes_once_complete::<Address>(&[0x01, 192, 0, 2, 1, 0, 80]);
+        let mut v6 = alloc::vec![0x04];
+        v6.extend_from_slice(&[0x20; 18]);
+        decodes_once_complete::<Address>(&v6);
+        decodes_once_complete::<Address>(&[0x03, 3, b'a', b'.', b'b', 0, 80]);
+        decodes_once_complete::<TcpRequestHeader>(&request().encode());
+        let response = TcpResponseHeader::failure(Replies::ConnectionRefused);
+        decodes_once_complete::<TcpResponseHeader>(&response.encode());
+        decodes_once_complete::<PasswordRequest>(&[2, b'm', b'e', 1, b'x']);
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn blocking_handshake_over_std_streams() {
+        use std::{
+            net::{Ipv4Addr, TcpListener, TcpStream},
+            thread,
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let auth_req = <AuthenticationRequest as DecodeSync<_>>::read(&mut stream).unwrap();
+            assert_eq!(auth_req.methods(), [Method::NONE]);
+            let auth_resp = AuthenticationResponse::from(Method::NONE);
+            auth_resp.write_to_sync(&mut stream).unwrap();
+            let request = <TcpRequestHeader as DecodeSync<_>>::read(&mut stream).unwrap();
+            let response = TcpResponseHeader::success(addr.into());
+            response.write_to_sync(&mut stream).unwrap();
+            request
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let auth_req = AuthenticationRequest::new([Method::NONE]);
+        auth_req.write_to_sync(&mut stream).unwrap();
+        let auth_resp = <AuthenticationResponse as DecodeSync<_>>::read(&mut stream).unwrap();
+        assert_eq!(auth_resp.method(), Method::NONE);
+        request().write_to_sync(&mut stream).unwrap();
+        let response = <TcpResponseHeader as DecodeSync<_>>::read(&mut stream).unwrap();
+        assert!(response.is_success());
+        assert_eq!(response.address(), &Address::from(addr));
+        assert_eq!(server.join().unwrap(), request());
+    }
+
+    /// Sends the version byte, then nothing
+    #[cfg(feature = "timeout")]
+    struct Stalling {
+        sent: bool,
+    }
+
+    #[cfg(feature = "timeout")]
+    impl AsyncRead for Stalling {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            out: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.sent {
+                return Poll::Pending;
+            }
+            self.sent = true;
+            out[0] = VERSION;
+            Poll::Ready(Ok(1))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "timeout")]
+    fn read_timeout_expires_when_the_peer_stalls() {
+        let mut stalling = Stalling { sent: false };
+        let timeout = Duration::from_millis(50);
+        let err = block_on(TcpRequestHeader::read_timeout(&mut stalling, timeout)).unwrap_err();
+        assert!(stalling.sent);
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(err.reply, Replies::TtlExpired);
+    }
+
+    fn encoded(message: impl Encode) -> Bytes {
+        message.as_bytes()
+    }
+
+    #[test]
+    fn pointers_to_messages_are_encode() {
+        let request = request();
+        let expected = request.as_bytes();
+        assert_eq!(encoded(&request), expected);
+        assert_eq!(encoded(Box::new(request.clone())), expected);
+        assert_eq!(encoded(Arc::new(request)), expected);
+    }
+
+    #[test]
+    fn read_counted_counts_the_version_and_stops_at_the_message() {
+        let request = request();
+        let mut input = request.as_bytes().to_vec();
+        input.extend_from_slice(b"payload");
+        let mut reader = &input[..];
+        let (decoded, n) = block_on(read_counted::<TcpRequestHeader, _>(&mut reader)).unwrap();
+        assert_eq!(decoded, request);
+        assert_eq!(n, request.encoded_len() + 1);
+        assert_eq!(reader, b"payload");
+    }
+
+    #[test]
+    fn declared_length_over_the_frame_limit_is_refused() {
+        let limits = DecodeLimits {
+            max_frame_len: 64,
+            ..DecodeLimits::default()
+        };
+        // a GSSAPI message declaring a token of 1000 bytes
+        let mut input = alloc::vec![1, 1, 0x03, 0xe8];
+        input.resize(4 + 1000, 0xaa);
+        let mut reader = &input[..];
+        let err = block_on(read_limited::<GssapiMessage, _>(&mut reader, &limits)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+        assert_eq!(err.reply, Replies::GeneralFailure);
+        // refused before the token is read
+        assert_eq!(reader.len(), 1000);
+
+        let mut reader = &input[..];
+        let mut decoder = StreamDecoder::with_limits(limits);
+        let err = block_on(decoder.read::<GssapiMessage, _>(&mut reader)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+        assert_eq!(err.reply, Replies::GeneralFailure);
+        assert_eq!(reader.len(), 1000);
+
+        let token = alloc::vec![0xaa; 32];
+        let message = GssapiMessage::new(GssapiMessageType::Authentication, token).unwrap();
+        let input = message.as_bytes();
+        let decoded = block_on(read_limited::<GssapiMessage, _>(&mut &input[..], &limits));
+        assert_eq!(decoded.unwrap(), (message, 1 + 3 + 32));
+    }
+
+    /// Feeds `input` to `parser`, expecting exactly one message of its whole length
+    fn parse_one(parser: &mut HandshakeParser, input: &[u8]) -> Message {
+        match parser.feed(input).unwrap() {
+            Progress::Parsed(message, consumed) => {
+                assert_eq!(consumed, input.len());
+                message
+            }
+            Progress::NeedMoreData(needed) => panic!("{needed} more bytes needed"),
+        }
+    }
+
+    #[test]
+    fn parser_asks_for_at_most_the_missing_bytes() {
+        let messages = [
+            (
+                Phase::AuthRequest,
+                AuthenticationRequest::new([Method::NONE]).as_bytes(),
+            ),
+            (Phase::Request, request().as_bytes()),
+            (
+                Phase::Response,
+                TcpResponseHeader::success(Address::from(SocketAddrV6::new(
+                    [0x20, 1, 0, 0, 0, 0, 0, 1].into(),
+                    1080,
+                    0,
+                    0,
+                )))
+                .as_bytes(),
+            ),
+        ];
+        for (phase, input) in messages {
+            for len in 0..input.len() {
+                let mut parser = HandshakeParser { phase: Some(phase) };
+                match parser.feed(&input[..len]).unwrap() {
+                    Progress::NeedMoreData(needed) => {
+                        assert!(needed >= 1, "{phase:?} after {len} bytes");
+                        assert!(len + needed <= input.len(), "{phase:?} after {len} bytes");
+                    }
+                    Progress::Parsed(..) => panic!("{phase:?} parsed from {len} bytes"),
+                }
+                assert_eq!(parser.phase(), Some(phase));
+            }
+            let mut parser = HandshakeParser { phase: Some(phase) };
+            match parser.feed(&input[..input.len() - 1]).unwrap() {
+                Progress::NeedMoreData(needed) => assert_eq!(needed, 1),
+                Progress::Parsed(..) => unreachable!(),
+            }
+            parse_one(&mut parser, &input);
+        }
+    }
+
+    #[test]
+    fn parser_follows_the_phases() {
+        let mut server = HandshakeParser::server();
+        let auth = AuthenticationRequest::new([Method::NONE, Method::PASSWORD]);
+        let parsed = parse_one(&mut server, &auth.as_bytes());
+        assert_eq!(parsed, Message::AuthRequest(auth));
+        assert_eq!(server.phase(), Some(Phase::Request));
+        server.select_method(Method::PASSWORD);
+        assert_eq!(server.phase(), Some(Phase::PasswordRequest));
+        let password = PasswordRequest::new(b"alice", b"secret").unwrap();
+        let parsed = parse_one(&mut server, &password.as_bytes());
+        assert_eq!(parsed, Message::PasswordRequest(password));
+        assert_eq!(server.phase(), Some(Phase::Request));
+        let parsed = parse_one(&mut server, &request().as_bytes());
+        assert_eq!(parsed, Message::Request(request()));
+        assert_eq!(server.phase(), None);
+        assert!(server.feed(&request().as_bytes()).is_err());
+
+        let mut client = HandshakeParser::client();
+        let auth = AuthenticationResponse::from(Method::PASSWORD);
+        parse_one(&mut client, &auth.as_bytes());
+        assert_eq!(client.phase(), Some(Phase::PasswordResponse));
+        parse_one(&mut client, &PasswordResponse::success().as_bytes());
+        assert_eq!(client.phase(), Some(Phase::Response));
+        let response = TcpResponseHeader::failure(Replies::ConnectionRefused);
+        let parsed = parse_one(&mut client, &response.as_bytes());
+        assert_eq!(parsed, Message::Response(response));
+        assert_eq!(client.phase(), None);
+
+        let mut client = HandshakeParser::client();
+        parse_one(
+            &mut client,
+            &AuthenticationResponse::from(Method::NONE).as_bytes(),
+        );
+        assert_eq!(client.phase(), Some(Phase::Response));
+    }
+
+    #[test]
+    fn parser_consumes_one_message_at_a_time() {
+        let auth = AuthenticationRequest::new([Method::NONE]).as_bytes();
+        let mut input = auth.to_vec();
+        input.extend_from_slice(&request().as_bytes());
+        let mut server = HandshakeParser::server();
+        let consumed = match server.feed(&input).unwrap() {
+            Progress::Parsed(Message::AuthRequest(_), consumed) => consumed,
+            _ => panic!("authentication request not parsed"),
+        };
+        assert_eq!(consumed, auth.len());
+        let parsed = parse_one(&mut server, &input[consumed..]);
+        assert_eq!(parsed, Message::Request(request()));
+    }
+
+    #[test]
+    fn stream_decoder_resumes_after_a_dropped_read() {
+        use futures_lite::{future::poll_once, AsyncWriteExt};
+
+        let input = request().as_bytes();
+        let (mut reader, mut writer) = piper::pipe(64);
+        let mut decoder = StreamDecoder::new();
+        block_on(async {
+            writer.write_all(&input[..5]).await.unwrap();
+            // reads the 5 bytes, then waits for the rest and is dropped
+            let read = decoder.read::<TcpRequestHeader, _>(&mut reader);
+            assert!(poll_once(read).await.is_none());
+            assert!(decoder.is_partial());
+            writer.write_all(&input[5..]).await.unwrap();
+            let decoded = decoder.read::<TcpRequestHeader, _>(&mut reader).await;
+            assert_eq!(decoded.unwrap(), request());
+        });
+        assert!(!decoder.is_partial());
+    }
+
+    #[test]
+    fn read_buffered_returns_the_pipelined_data() {
+        let pipelined = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let mut input = request().as_bytes().to_vec();
+        input.extend_from_slice(pipelined);
+        let mut reader = &input[..];
+        let (decoded, rest) = block_on(read_buffered::<TcpRequestHeader, _>(&mut reader)).unwrap();
+        assert_eq!(decoded, request());
+        assert_eq!(rest, &pipelined[..]);
+
+        let input = request().as_bytes();
+        let (_, rest) = block_on(read_buffered::<TcpRequestHeader, _>(&mut &input[..])).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn encode_into_is_enough_to_implement_encode() {
+        struct Pong;
+
+        impl Encode for Pong {
+            fn encode_into(&self, buffer: &mut BytesMut) {
+                buffer.put_slice(b"pong");
+            }
+        }
+
+        assert_eq!(Pong.encoded_len(), 4);
+        assert_eq!(Pong.encode(), &b"pong"[..]);
+        assert_eq!(Pong.as_bytes(), &b"\x05pong"[..]);
+    }
+}
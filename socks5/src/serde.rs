@@ -0,0 +1,260 @@
+//! [`Serialize`] and [`Deserialize`] implementations of the message enums, as
+//! their `Display` names in human-readable formats and as their raw byte in
+//! binary ones
+
+use core::{
+    fmt::{self, Display, Formatter},
+    marker::PhantomData,
+};
+
+use serde::{
+    de::{self, Unexpected, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::message::{Command, Method, Replies};
+
+/// Enum serialized by name or by its byte on the wire
+trait Named: Copy + TryFrom<u8> + 'static {
+    /// Kind of value, for error messages
+    const EXPECTING: &'static str;
+
+    fn all() -> &'static [Self];
+
+    /// `None` for values without a name, serialized by byte in any format
+    fn name(self) -> Option<&'static str>;
+
+    fn byte(self) -> u8;
+}
+
+impl Named for Command {
+    const EXPECTING: &'static str = "a SOCKS5 command";
+
+    fn all() -> &'static [Self] {
+        &Command::ALL
+    }
+
+    fn name(self) -> Option<&'static str> {
+        Some(self.as_str())
+    }
+
+    fn byte(self) -> u8 {
+        self.as_u8()
+    }
+}
+
+impl Named for Method {
+    const EXPECTING: &'static str = "a SOCKS5 authentication method";
+
+    fn all() -> &'static [Self] {
+        &Method::ALL
+    }
+
+    fn name(self) -> Option<&'static str> {
+        Some(self.as_str())
+    }
+
+    fn byte(self) -> u8 {
+        self.as_u8()
+    }
+}
+
+impl Named for Replies {
+    const EXPECTING: &'static str = "a SOCKS5 reply";
+
+    fn all() -> &'static [Self] {
+        &Replies::ALL
+    }
+
+    fn name(self) -> Option<&'static str> {
+        match self {
+            Replies::Unassigned(_) => None,
+            _ => Some(self.as_str()),
+        }
+    }
+
+    fn byte(self) -> u8 {
+        self.as_u8()
+    }
+}
+
+fn serialize<T: Named, S: Serializer>(value: T, serializer: S) -> Result<S::Ok, S::Error> {
+    match value.name() {
+        Some(name) if serializer.is_human_readable() => serializer.serialize_str(name),
+        _ => serializer.serialize_u8(value.byte()),
+    }
+}
+
+fn deserialize<'de, T: Named, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(NamedVisitor(PhantomData))
+    } else {
+        deserializer.deserialize_u8(NamedVisitor(PhantomData))
+    }
+}
+
+struct NamedVisitor<T>(PhantomData<T>);
+
+impl<T: Named> Visitor<'_> for NamedVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(T::EXPECTING)
+    }
+
+    /// Matches names ignoring case, like the `FromStr` impl of [`Replies`]
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        T::all()
+            .iter()
+            .find(|value| {
+                value
+                    .name()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(v))
+            })
+            .copied()
+            .ok_or_else(|| E::custom(UnknownName::<T>(v, PhantomData)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+        u8::try_from(v)
+            .ok()
+            .and_then(|v| T::try_from(v).ok())
+            .ok_or_else(|| E::invalid_value(Unexpected::Unsigned(v), &self))
+    }
+}
+
+/// Error message listing the valid names
+struct UnknownName<'a, T>(&'a str, PhantomData<T>);
+
+impl<T: Named> Display for UnknownName<'_, T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "unknown name {:?}, expected one of ", self.0)?;
+        for (i, value) in T::all().iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{:?}", value.name().unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Command {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(*self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Command {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer)
+    }
+}
+
+impl Serialize for Method {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(*self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Method {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer)
+    }
+}
+
+impl Serialize for Replies {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(*self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Replies {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+
+    fn cbor<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(value, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn json_round_trips_by_name() {
+        for command in Command::ALL {
+            let json = serde_json::to_string(&command).unwrap();
+            assert_eq!(json, alloc::format!("\"{command}\""));
+            assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), command);
+        }
+        for method in Method::ALL {
+            let json = serde_json::to_string(&method).unwrap();
+            assert_eq!(json, alloc::format!("\"{method}\""));
+            assert_eq!(serde_json::from_str::<Method>(&json).unwrap(), method);
+        }
+        for reply in Replies::ALL {
+            let json = serde_json::to_string(&reply).unwrap();
+            assert_eq!(serde_json::from_str::<Replies>(&json).unwrap(), reply);
+        }
+        let json = serde_json::to_string(&Replies::TtlExpired).unwrap();
+        assert_eq!(json, "\"TTL expired\"");
+        let reply: Replies = serde_json::from_str("\"ttl EXPIRED\"").unwrap();
+        assert_eq!(reply, Replies::TtlExpired);
+    }
+
+    #[test]
+    fn binary_round_trips_by_byte() {
+        for command in Command::ALL {
+            let buf = cbor(&command);
+            assert_eq!(buf, cbor(&command.as_u8()));
+            assert_eq!(
+                ciborium::from_reader::<Command, _>(&buf[..]).unwrap(),
+                command
+            );
+        }
+        for method in Method::ALL {
+            let buf = cbor(&method);
+            assert_eq!(buf, cbor(&method.as_u8()));
+            assert_eq!(
+                ciborium::from_reader::<Method, _>(&buf[..]).unwrap(),
+                method
+            );
+        }
+        for reply in Replies::ALL.into_iter().chain([Replies::Unassigned(0x42)]) {
+            let buf = cbor(&reply);
+            assert_eq!(buf, cbor(&reply.as_u8()));
+            assert_eq!(
+                ciborium::from_reader::<Replies, _>(&buf[..]).unwrap(),
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_name_lists_the_expected_ones() {
+        let err = serde_json::from_str::<Command>("\"LISTEN\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown name \"LISTEN\", expected one of \"CONNECT\", \"BIND\", \"UDP_ASSOCIATE\" \
+             at line 1 column 8"
+        );
+        let err = serde_json::from_str::<Method>("300").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected a SOCKS5 authentication method"));
+    }
+
+    #[test]
+    fn unassigned_reply_is_a_number() {
+        let reply = Replies::Unassigned(0x42);
+        assert_eq!(serde_json::to_string(&reply).unwrap(), "66");
+        assert_eq!(serde_json::from_str::<Replies>("66").unwrap(), reply);
+    }
+}